@@ -1,17 +1,57 @@
 //! The representation of the key and value in the in-memory phase.
 
 use core::hash;
-use std::cmp;
+#[cfg(feature = "custom-alloc")]
+use std::alloc::GlobalAlloc;
+#[cfg(debug_assertions)]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::{
+    alloc::Layout,
+    cmp,
+    hash::{DefaultHasher, Hasher},
+};
 
 // Bytes is a struct that implement cheap clone
 // and can be safely transfer between threads.
 // Bytes control the lifetime of its value.
 pub struct Bytes {
-    ptr: *const u8,
-    len: usize,
-    cap: usize,
+    repr: Repr,
+    // Shared by a heap buffer's owner and all of its views, and cleared when
+    // the owner frees the buffer, so that a dangling view panics on use.
+    #[cfg(debug_assertions)]
+    live: Option<Arc<AtomicBool>>,
 }
 
+/// Copies of at most this many bytes are stored inline in the `Bytes` itself,
+/// without a heap allocation.
+pub const INLINE_CAP: usize = 30;
+
+#[derive(Clone, Copy)]
+enum Repr {
+    // A buffer on the heap or in static memory. It is owned, and freed when
+    // `cap != 0`.
+    Ptr {
+        ptr: *const u8,
+        len: usize,
+        cap: usize,
+        // The allocator of the heap buffer, or `None` for the global one.
+        #[cfg(feature = "custom-alloc")]
+        alloc: Option<&'static BytesAllocator>,
+    },
+    // A short payload stored in place.
+    Inline {
+        len: u8,
+        data: [u8; INLINE_CAP],
+    },
+}
+
+/// An allocator that can back the heap buffer of a `Bytes`.
+#[cfg(feature = "custom-alloc")]
+pub type BytesAllocator = dyn GlobalAlloc + Sync;
+
 const EMPTY: &[u8] = &[];
 
 impl Bytes {
@@ -22,18 +62,270 @@ impl Bytes {
     #[inline]
     pub const fn from_static(bytes: &'static [u8]) -> Self {
         Self {
-            ptr: bytes.as_ptr(),
-            len: bytes.len(),
-            cap: 0,
+            repr: Repr::Ptr {
+                ptr: bytes.as_ptr(),
+                len: bytes.len(),
+                cap: 0,
+                #[cfg(feature = "custom-alloc")]
+                alloc: None,
+            },
+            #[cfg(debug_assertions)]
+            live: None,
+        }
+    }
+
+    /// Copy `data` into a new buffer from the global allocator, or inline if
+    /// it is at most [`INLINE_CAP`] bytes.
+    pub fn copy_from_slice(data: &[u8]) -> Self {
+        if data.len() <= INLINE_CAP {
+            return Self::inline(&[data]);
+        }
+        data.to_vec().into()
+    }
+
+    /// Copy `data` into a buffer allocated by `alloc`, which also frees it
+    /// once the returned `Bytes` is dropped. Like `copy_from_slice`, short
+    /// data is stored inline without calling `alloc`.
+    #[cfg(feature = "custom-alloc")]
+    pub fn copy_from_slice_in(data: &[u8], alloc: &'static BytesAllocator) -> Self {
+        if data.len() <= INLINE_CAP {
+            return Self::inline(&[data]);
+        }
+
+        let layout = Layout::from_size_align(data.len(), 1).unwrap();
+        // SAFETY:
+        // `layout` has a non-zero size, and the new buffer is valid for
+        // `data.len()` bytes, so the copy stays in bounds.
+        let ptr = unsafe {
+            let ptr = alloc.alloc(layout);
+            if ptr.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+            ptr
+        };
+        let mut bytes = Self::from_owned(ptr, data.len(), data.len());
+        if let Repr::Ptr {
+            alloc: ref mut owner,
+            ..
+        } = bytes.repr
+        {
+            *owner = Some(alloc);
+        }
+        bytes
+    }
+
+    /// Copy the bytes into a new `Bytes` that owns them, so unlike a clone it
+    /// can outlive `self`. A heap copy comes from the same allocator as
+    /// `self`, and short data is stored inline.
+    pub fn deep_copy(&self) -> Bytes {
+        match self.repr {
+            #[cfg(feature = "custom-alloc")]
+            Repr::Ptr {
+                alloc: Some(alloc), ..
+            } => Self::copy_from_slice_in(self.as_slice(), alloc),
+            _ => Self::copy_from_slice(self.as_slice()),
+        }
+    }
+
+    /// Get a 64-bit hash of the bytes, for cache lookups. Equal bytes always
+    /// hash the same. It's computed afresh on every call; wrap a key that is
+    /// looked up repeatedly in a [`HashedBytes`] to compute it only once.
+    pub fn hash64(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(self.as_slice());
+        hasher.finish()
+    }
+
+    /// Concatenate `parts` into a single new buffer, allocated once for the
+    /// total length.
+    pub fn concat(parts: &[&[u8]]) -> Self {
+        let len = parts.iter().map(|part| part.len()).sum();
+        if len <= INLINE_CAP {
+            return Self::inline(parts);
+        }
+        let mut buf = Vec::with_capacity(len);
+        for part in parts {
+            buf.extend_from_slice(part);
+        }
+        buf.into()
+    }
+
+    /// Convert into a `Vec<u8>`. An owned heap buffer from the global
+    /// allocator is handed over without copying, anything else is copied.
+    ///
+    /// Handing over the buffer ends it the same way dropping does, so existing
+    /// views of it must not be used afterwards.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        #[cfg(debug_assertions)]
+        assert!(
+            !(self.is_owned() && self.is_static()),
+            "Bytes claims to own static memory"
+        );
+        let in_global = self.in_global();
+        match self.repr {
+            Repr::Ptr {
+                ptr,
+                len,
+                ref mut cap,
+                ..
+            } if *cap != 0 && in_global => {
+                // Give up ownership, so that dropping `self` won't free the buffer.
+                let cap = std::mem::take(cap);
+                #[cfg(debug_assertions)]
+                if let Some(live) = &self.live {
+                    live.store(false, Ordering::Release);
+                }
+                // SAFETY:
+                // `ptr` was allocated by the global allocator with a capacity of
+                // `cap` bytes, `len` of which are initialized, and `self` no
+                // longer owns it.
+                unsafe { Vec::from_raw_parts(ptr as *mut u8, len, cap) }
+            }
+            _ => self.as_slice().to_vec(),
+        }
+    }
+
+    // Store the concatenated `parts`, at most `INLINE_CAP` bytes, inline.
+    fn inline(parts: &[&[u8]]) -> Self {
+        let mut data = [0; INLINE_CAP];
+        let mut len = 0;
+        for part in parts {
+            data[len..len + part.len()].copy_from_slice(part);
+            len += part.len();
+        }
+        Self {
+            repr: Repr::Inline {
+                len: len as u8,
+                data,
+            },
+            #[cfg(debug_assertions)]
+            live: None,
         }
     }
 
+    // Whether `self` owns its heap buffer, and frees it when dropped.
+    fn is_owned(&self) -> bool {
+        matches!(self.repr, Repr::Ptr { cap, .. } if cap != 0)
+    }
+
+    // Whether `self` points into static memory rather than a heap buffer or
+    // inline data. Heap buffers and their views always carry a liveness flag
+    // and static memory never does, so only debug builds can tell.
+    #[cfg(debug_assertions)]
+    fn is_static(&self) -> bool {
+        matches!(self.repr, Repr::Ptr { .. }) && self.live.is_none()
+    }
+
+    // Whether a heap buffer, if any, comes from the global allocator, like
+    // the buffer of a `Vec<u8>`.
+    fn in_global(&self) -> bool {
+        #[cfg(feature = "custom-alloc")]
+        if let Repr::Ptr { alloc: Some(_), .. } = self.repr {
+            return false;
+        }
+        true
+    }
+
+    // Take ownership of a heap buffer from the global allocator.
+    fn from_owned(ptr: *const u8, len: usize, cap: usize) -> Self {
+        Self {
+            repr: Repr::Ptr {
+                ptr,
+                len,
+                cap,
+                #[cfg(feature = "custom-alloc")]
+                alloc: None,
+            },
+            #[cfg(debug_assertions)]
+            live: Some(Arc::new(AtomicBool::new(true))),
+        }
+    }
+
+    // A view of `len` bytes starting at `offset`, which the caller has checked
+    // are in bounds. A heap view shares the buffer without owning it, while
+    // inline data is simply copied.
+    fn view(&self, offset: usize, len: usize) -> Self {
+        let mut repr = self.repr;
+        match &mut repr {
+            Repr::Ptr {
+                ptr,
+                len: view_len,
+                cap,
+                ..
+            } => {
+                // SAFETY: `offset` is within the buffer.
+                *ptr = unsafe { ptr.add(offset) };
+                *view_len = len;
+                // Set the capacity to zero to prevent double free.
+                *cap = 0;
+            }
+            Repr::Inline {
+                len: view_len,
+                data,
+            } => {
+                data.copy_within(offset..offset + len, 0);
+                *view_len = len as u8;
+            }
+        }
+        Self {
+            repr,
+            #[cfg(debug_assertions)]
+            live: self.live.clone(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self.repr {
+            Repr::Ptr { len, .. } => len,
+            Repr::Inline { len, .. } => len as usize,
+        }
+    }
+
+    /// Get a view of the first `len` bytes.
+    ///
+    /// Like `clone`, the view shares the buffer without owning it, so it must
+    /// not outlive `self`. Panics if `len` is greater than the length.
+    pub fn truncated(&self, len: usize) -> Bytes {
+        assert!(
+            len <= self.len(),
+            "truncate length {} exceeds length {}",
+            len,
+            self.len()
+        );
+        self.view(0, len)
+    }
+
+    /// Split into views of the bytes before and from `mid`.
+    ///
+    /// Like `clone`, both views share the buffer without owning it, so they
+    /// must not outlive `self`. Panics if `mid` is greater than the length.
+    pub fn split_at(&self, mid: usize) -> (Bytes, Bytes) {
+        assert!(
+            mid <= self.len(),
+            "split index {} exceeds length {}",
+            mid,
+            self.len()
+        );
+        (self.view(0, mid), self.view(mid, self.len() - mid))
+    }
+
     #[inline]
     fn as_slice(&self) -> &[u8] {
-        // SAFETY:
-        // `self.ptr` points to valid memory for at least `self.len` bytes.
-        // `self.ptr` is properly aligned for `u8`
-        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+        #[cfg(debug_assertions)]
+        if let Some(live) = &self.live {
+            assert!(
+                live.load(Ordering::Acquire),
+                "Bytes used after its backing allocation was freed by its owner"
+            );
+        }
+        match self.repr {
+            // SAFETY:
+            // `ptr` points to valid memory for at least `len` bytes.
+            // `ptr` is properly aligned for `u8`
+            Repr::Ptr { ptr, len, .. } => unsafe { std::slice::from_raw_parts(ptr, len) },
+            Repr::Inline { len, ref data } => &data[..len as usize],
+        }
     }
 }
 
@@ -61,31 +353,69 @@ impl From<Vec<u8>> for Bytes {
 
         // Prevent Vec from deallocating.
         std::mem::forget(vec);
-        Bytes { ptr, len, cap }
+        Bytes::from_owned(ptr, len, cap)
     }
 }
 
 impl From<&[u8]> for Bytes {
     fn from(slices: &[u8]) -> Bytes {
-        let vec = slices.to_vec();
-        vec.into()
+        Bytes::copy_from_slice(slices)
+    }
+}
+
+// Avoids copying when the `bytes::Bytes` uniquely owns a `Vec`-backed buffer.
+#[cfg(feature = "bytes-interop")]
+impl From<bytes::Bytes> for Bytes {
+    fn from(bytes: bytes::Bytes) -> Bytes {
+        Vec::from(bytes).into()
+    }
+}
+
+// Moves an owned buffer over without copying, see `Bytes::into_vec`; views of
+// it must not be used afterwards. Views, static and inline data are copied.
+#[cfg(feature = "bytes-interop")]
+impl From<Bytes> for bytes::Bytes {
+    fn from(bytes: Bytes) -> bytes::Bytes {
+        bytes.into_vec().into()
     }
 }
 
 // SAFETY:
-// 1. `self.ptr` was originally obtained from a heap allocation (via `Vec<u8>`)
+// 1. `ptr` was originally obtained from a heap allocation, either a `Vec<u8>`
+//    through the global allocator or `copy_from_slice_in` through `alloc`,
 //    and has not been moved or deallocated elsewhere before this `Drop` call.
-// 2. The alignment of `u8` is 1, so using `Layout::from_size_align(self.cap, 1)`
-//    is valid and matches the allocation made by the original `Vec<u8>`.
+// 2. The alignment of `u8` is 1, so using `Layout::from_size_align(cap, 1)`
+//    is valid and matches the original allocation.
 impl Drop for Bytes {
     fn drop(&mut self) {
-        if self.cap != 0 {
-            unsafe {
-                std::alloc::dealloc(
-                    self.ptr as *mut u8,
-                    std::alloc::Layout::from_size_align(self.cap, 1).unwrap(),
-                )
+        if !self.is_owned() {
+            return;
+        }
+        // Leak rather than free memory that no allocator handed out. Unwinding
+        // from the same check in `into_vec` drops `self`, so don't panic twice.
+        #[cfg(debug_assertions)]
+        if self.is_static() {
+            assert!(
+                std::thread::panicking(),
+                "Bytes claims to own static memory"
+            );
+            return;
+        }
+        #[cfg(debug_assertions)]
+        if let Some(live) = &self.live {
+            live.store(false, Ordering::Release);
+        }
+        if let Repr::Ptr { ptr, cap, .. } = self.repr {
+            let layout = Layout::from_size_align(cap, 1).unwrap();
+            #[cfg(feature = "custom-alloc")]
+            if let Repr::Ptr {
+                alloc: Some(alloc), ..
+            } = self.repr
+            {
+                unsafe { alloc.dealloc(ptr as *mut u8, layout) };
+                return;
             }
+            unsafe { std::alloc::dealloc(ptr as *mut u8, layout) }
         }
     }
 }
@@ -102,6 +432,10 @@ impl PartialOrd for Bytes {
     }
 }
 
+// Bytes compare lexicographically, exactly like `[u8]`: the first differing
+// byte decides, and when one is a prefix of the other the shorter one is
+// smaller, so the empty Bytes sorts first. This holds whatever the
+// representation (static, heap or inline) of either side.
 impl Ord for Bytes {
     fn cmp(&self, other: &Bytes) -> cmp::Ordering {
         self.as_slice().cmp(other.as_slice())
@@ -122,31 +456,125 @@ impl Eq for Bytes {}
 impl Clone for Bytes {
     #[inline]
     fn clone(&self) -> Bytes {
-        Self {
-            ptr: self.ptr,
-            len: self.len,
-            // Set the capacity to zero to prevent double free.
-            cap: 0,
-        }
+        self.view(0, self.len())
     }
 }
 
 impl std::fmt::Debug for Bytes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Bytes")
-            .field("actual value", &self.as_slice())
-            .field("ptr", &format_args!("0x{:x}", self.ptr as usize))
-            .field("len", &self.len)
-            .field("cap", &self.cap)
-            .finish()
+        let mut f = f.debug_struct("Bytes");
+        f.field("actual value", &self.as_slice());
+        match self.repr {
+            Repr::Ptr { ptr, len, cap, .. } => f
+                .field("ptr", &format_args!("0x{:x}", ptr as usize))
+                .field("len", &len)
+                .field("cap", &cap),
+            Repr::Inline { len, .. } => f.field("inline", &true).field("len", &len),
+        };
+        f.finish()
     }
 }
 
 unsafe impl Send for Bytes {}
 unsafe impl Sync for Bytes {}
 
-pub trait ByteReader {}
+/// A `Bytes` with its [`Bytes::hash64`] computed once, up front, for use as a
+/// cache key: hashing it only feeds in the cached value, and comparing two of
+/// them checks the hashes before the bytes.
+#[derive(Clone, Debug)]
+pub struct HashedBytes {
+    bytes: Bytes,
+    hash: u64,
+}
+
+impl HashedBytes {
+    pub fn new(bytes: Bytes) -> Self {
+        let hash = bytes.hash64();
+        Self { bytes, hash }
+    }
+
+    pub fn hash64(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
+    }
+}
+
+impl AsRef<[u8]> for HashedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+}
+
+impl PartialEq for HashedBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.bytes == other.bytes
+    }
+}
+
+impl Eq for HashedBytes {}
 
+impl hash::Hash for HashedBytes {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: hash::Hasher,
+    {
+        state.write_u64(self.hash);
+    }
+}
+
+/// Reads big-endian integers from the front of a buffer, advancing past them.
+pub trait ByteReader {
+    fn remaining(&self) -> usize;
+
+    fn advance(&mut self, cnt: usize);
+
+    fn read_u16(&mut self) -> Option<u16>;
+
+    fn read_u32(&mut self) -> Option<u32>;
+
+    fn read_u64(&mut self) -> Option<u64>;
+}
+
+impl ByteReader for &[u8] {
+    fn remaining(&self) -> usize {
+        self.len()
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        *self = &self[cnt..];
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        let (bytes, rest) = self.split_first_chunk::<2>()?;
+        *self = rest;
+        Some(u16::from_be_bytes(*bytes))
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let (bytes, rest) = self.split_first_chunk::<4>()?;
+        *self = rest;
+        Some(u32::from_be_bytes(*bytes))
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        let (bytes, rest) = self.split_first_chunk::<8>()?;
+        *self = rest;
+        Some(u64::from_be_bytes(*bytes))
+    }
+}
+
+/// Big-endian integer encoding for a byte buffer used as a stack.
+///
+/// `put_*` appends to the end and `get_*` pops from the end, so values come
+/// back in LIFO order: the last value put is the first one got. To read values
+/// front to back, in the order they were written, use [`ByteReader`] instead.
 pub trait ByteUtil {
     fn put_u16(&mut self, val: u16);
 
@@ -154,10 +582,13 @@ pub trait ByteUtil {
 
     fn put_u64(&mut self, val: u64);
 
+    /// Pop a `u16` off the end, or `None` if fewer than 2 bytes are left.
     fn get_u16(&mut self) -> Option<u16>;
 
+    /// Pop a `u32` off the end, or `None` if fewer than 4 bytes are left.
     fn get_u32(&mut self) -> Option<u32>;
 
+    /// Pop a `u64` off the end, or `None` if fewer than 8 bytes are left.
     fn get_u64(&mut self) -> Option<u64>;
 }
 
@@ -213,8 +644,30 @@ impl ByteUtil for Vec<u8> {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "custom-alloc")]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use super::*;
 
+    #[cfg(feature = "custom-alloc")]
+    struct CountingAlloc {
+        allocs: AtomicUsize,
+        frees: AtomicUsize,
+    }
+
+    #[cfg(feature = "custom-alloc")]
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.allocs.fetch_add(1, Ordering::SeqCst);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.frees.fetch_add(1, Ordering::SeqCst);
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
     #[test]
     fn test_bytes_drop() {
         let mut v = Vec::with_capacity(1000);
@@ -266,6 +719,40 @@ mod tests {
         assert!(b1 < b2);
         assert!(b1 < b3);
         assert!(b2 < b3);
+
+        let empty = Bytes::new();
+        assert_eq!(empty, Bytes::from(vec![]));
+        assert!(empty < Bytes::from_static(&[0]));
+        assert!(empty < b1);
+
+        // Every prefix sorts before every longer prefix, whether each side is
+        // stored inline or on the heap.
+        let data: Vec<u8> = (0..2 * INLINE_CAP as u8).collect();
+        for i in 0..=data.len() {
+            for j in 0..=data.len() {
+                let short = Bytes::copy_from_slice(&data[..i]);
+                let long = Bytes::from(data[..j].to_vec());
+                assert_eq!(short.cmp(&long), i.cmp(&j), "{} vs {}", i, j);
+                assert_eq!(long.cmp(&short), j.cmp(&i), "{} vs {}", j, i);
+                assert_eq!(short == long, i == j);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes_size() {
+        // The inline data and its length fill what the tag leaves of the
+        // pointer variant, and a custom allocator is a fat pointer on top.
+        let mut expected = 32;
+        if cfg!(feature = "custom-alloc") {
+            expected += 16;
+        }
+        assert_eq!(std::mem::size_of::<Repr>(), expected);
+        // Besides that, only the liveness flag of debug builds: no hash.
+        if cfg!(debug_assertions) {
+            expected += 8;
+        }
+        assert_eq!(std::mem::size_of::<Bytes>(), expected);
     }
 
     #[test]
@@ -277,6 +764,252 @@ mod tests {
         assert_eq!(b1.as_ref(), [1, 2, 3]);
     }
 
+    #[test]
+    fn test_bytes_inline() {
+        let data: Vec<u8> = (0..=INLINE_CAP as u8).collect();
+        let inline = Bytes::copy_from_slice(&data[..INLINE_CAP]);
+        assert!(matches!(inline.repr, Repr::Inline { .. }));
+        let heap = Bytes::copy_from_slice(&data);
+        assert!(heap.is_owned());
+
+        assert_eq!(inline.as_ref(), &data[..INLINE_CAP]);
+        assert_eq!(heap.as_ref(), &data[..]);
+        assert!(inline < heap);
+        assert_eq!(inline, heap.truncated(INLINE_CAP));
+        assert_eq!(inline.truncated(2).as_ref(), [0, 1]);
+        assert_eq!(inline.clone(), inline);
+
+        let concat = Bytes::concat(&[&data[..10], &data[10..INLINE_CAP]]);
+        assert_eq!(concat, inline);
+
+        // Many tiny values never reach the allocator.
+        let tiny: Vec<_> = (0..10_000u64)
+            .map(|i| Bytes::copy_from_slice(&i.to_be_bytes()))
+            .collect();
+        assert_eq!(tiny[42].as_ref(), 42u64.to_be_bytes());
+        assert!(tiny.iter().all(|b| matches!(b.repr, Repr::Inline { .. })));
+    }
+
+    #[test]
+    fn test_bytes_into_vec() {
+        let data: Vec<u8> = (0..100).collect();
+        let owned = data.clone();
+        let ptr = owned.as_ptr();
+        let v = Bytes::from(owned).into_vec();
+        assert_eq!(v, data);
+        assert_eq!(v.as_ptr(), ptr);
+
+        assert_eq!(Bytes::from_static(b"static").into_vec(), b"static");
+        assert_eq!(Bytes::copy_from_slice(b"inline").into_vec(), b"inline");
+        let owner = Bytes::from(data.clone());
+        assert_eq!(owner.truncated(3).into_vec(), [0, 1, 2]);
+        assert_eq!(owner.as_ref(), &data[..]);
+    }
+
+    #[test]
+    fn test_bytes_static_not_owned() {
+        static DATA: [u8; 64] = [7; 64];
+        let b = Bytes::from_static(&DATA);
+        assert!(!b.is_owned());
+        let v = b.clone().into_vec();
+        assert_eq!(v, DATA);
+        assert_ne!(v.as_ptr(), DATA.as_ptr());
+        drop(b);
+
+        let owned = Bytes::from(DATA.to_vec());
+        assert!(owned.is_owned());
+        assert!(!owned.truncated(3).is_owned());
+        assert!(!Bytes::copy_from_slice(b"inline").is_owned());
+    }
+
+    // A `Bytes` over static memory that wrongly claims to own it, as a bug in
+    // a constructor could build.
+    #[cfg(debug_assertions)]
+    fn static_claiming_ownership() -> Bytes {
+        static DATA: [u8; 64] = [7; 64];
+        let b = Bytes::from_static(&DATA);
+        assert!(b.is_static());
+        assert!(!Bytes::from(DATA.to_vec()).is_static());
+        assert!(!Bytes::from(DATA.to_vec()).truncated(3).is_static());
+        assert!(!Bytes::copy_from_slice(b"inline").is_static());
+        Bytes {
+            repr: Repr::Ptr {
+                ptr: DATA.as_ptr(),
+                len: DATA.len(),
+                cap: DATA.len(),
+                #[cfg(feature = "custom-alloc")]
+                alloc: None,
+            },
+            live: None,
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Bytes claims to own static memory")]
+    fn test_bytes_static_into_vec() {
+        static_claiming_ownership().into_vec();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Bytes claims to own static memory")]
+    fn test_bytes_static_drop() {
+        drop(static_claiming_ownership());
+    }
+
+    #[test]
+    #[cfg(feature = "bytes-interop")]
+    fn test_bytes_interop() {
+        let data: Vec<u8> = (0..100).collect();
+        let owned = data.clone();
+        let ptr = owned.as_ptr();
+
+        let b: Bytes = bytes::Bytes::from(owned).into();
+        assert_eq!(b.as_ref(), &data[..]);
+        assert_eq!(b.as_ref().as_ptr(), ptr);
+
+        let back: bytes::Bytes = b.into();
+        assert_eq!(back, data);
+        assert_eq!(back.as_ptr(), ptr);
+
+        let b: Bytes = bytes::Bytes::from_static(b"static").into();
+        assert_eq!(b.as_ref(), b"static");
+        let back: bytes::Bytes = Bytes::copy_from_slice(b"inline").into();
+        assert_eq!(back, &b"inline"[..]);
+    }
+
+    #[test]
+    fn test_bytes_copy_from_slice() {
+        let data = vec![1, 2, 3];
+        let b = Bytes::copy_from_slice(&data);
+        drop(data);
+        assert_eq!(b.as_ref(), [1, 2, 3]);
+        assert!(Bytes::copy_from_slice(&[]).as_ref().is_empty());
+    }
+
+    #[test]
+    fn test_bytes_concat() {
+        assert!(Bytes::concat(&[]).as_ref().is_empty());
+        assert!(Bytes::concat(&[b"", b""]).as_ref().is_empty());
+        assert_eq!(Bytes::concat(&[b"tenant"]).as_ref(), b"tenant");
+
+        let b = Bytes::concat(&[b"tenant", b"", &[0], b"id"]);
+        assert_eq!(b.as_ref(), b"tenant\0id");
+    }
+
+    #[test]
+    fn test_bytes_truncated() {
+        let b = Bytes::from(vec![1, 2, 3, 4]);
+        assert_eq!(b.truncated(2).as_ref(), [1, 2]);
+        assert_eq!(b.truncated(4).as_ref(), [1, 2, 3, 4]);
+        assert!(b.truncated(0).as_ref().is_empty());
+
+        let t = b.truncated(3);
+        drop(t);
+        assert_eq!(b.as_ref(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "truncate length 5 exceeds length 4")]
+    fn test_bytes_truncated_too_long() {
+        let b = Bytes::from(vec![1, 2, 3, 4]);
+        b.truncated(5);
+    }
+
+    #[test]
+    fn test_bytes_split_at() {
+        let long = b"key region of the buffer|value region";
+        for data in [&b"keyvalue"[..], &long[..]] {
+            let b = Bytes::copy_from_slice(data);
+            for mid in [0, 3, data.len()] {
+                let (left, right) = b.split_at(mid);
+                assert_eq!(left.as_ref(), &data[..mid]);
+                assert_eq!(right.as_ref(), &data[mid..]);
+            }
+        }
+
+        // Heap-backed halves point into the original buffer.
+        let b = Bytes::copy_from_slice(long);
+        let (left, right) = b.split_at(24);
+        assert_eq!(left.as_ref().as_ptr(), b.as_ref().as_ptr());
+        assert_eq!(right.as_ref().as_ptr(), b.as_ref()[24..].as_ptr());
+        drop((left, right));
+        assert_eq!(b.as_ref(), long);
+    }
+
+    #[test]
+    fn test_bytes_deep_copy() {
+        let data: Vec<u8> = (0..100).collect();
+        let owner = Bytes::from(data.clone());
+        let view = owner.truncated(50);
+        let copy = view.deep_copy();
+        let short = owner.truncated(3).deep_copy();
+        assert_ne!(copy.as_ref().as_ptr(), owner.as_ref().as_ptr());
+        drop(view);
+        drop(owner);
+        assert_eq!(copy.as_ref(), &data[..50]);
+        assert_eq!(short.as_ref(), [0, 1, 2]);
+
+        let copy = Bytes::from_static(b"static, and too long to be inline").deep_copy();
+        assert_eq!(copy.as_ref(), b"static, and too long to be inline");
+        assert!(copy.is_owned());
+    }
+
+    #[test]
+    fn test_bytes_hash64() {
+        let long = b"a key too long to be stored inline";
+        let b = Bytes::copy_from_slice(long);
+        let hash = b.hash64();
+        assert_eq!(b.hash64(), hash);
+        assert_eq!(b.clone().hash64(), hash);
+        assert_eq!(b.split_at(0).1.hash64(), hash);
+        assert_eq!(Bytes::from_static(long).hash64(), hash);
+        assert_eq!(Bytes::from(long.to_vec()).hash64(), hash);
+        assert_eq!(b.deep_copy().hash64(), hash);
+
+        let prefix = b.truncated(5);
+        assert_eq!(prefix.hash64(), Bytes::copy_from_slice(&long[..5]).hash64());
+        assert_ne!(prefix.hash64(), hash);
+        assert_eq!(Bytes::copy_from_slice(b"").hash64(), Bytes::new().hash64());
+    }
+
+    #[test]
+    // The debug-only liveness flag in `Bytes` isn't hashed or compared.
+    #[allow(clippy::mutable_key_type)]
+    fn test_hashed_bytes() {
+        use std::collections::HashSet;
+
+        let long = b"a key too long to be stored inline";
+        let key = HashedBytes::new(Bytes::from_static(long));
+        assert_eq!(key.hash64(), Bytes::copy_from_slice(long).hash64());
+        assert_eq!(key.clone().hash64(), key.hash64());
+        assert_eq!(key.as_ref(), long);
+
+        let mut cache = HashSet::new();
+        cache.insert(key);
+        assert!(cache.contains(&HashedBytes::new(Bytes::from(long.to_vec()))));
+        assert!(!cache.contains(&HashedBytes::new(Bytes::from_static(b"other"))));
+    }
+
+    #[test]
+    #[should_panic(expected = "split index 5 exceeds length 4")]
+    fn test_bytes_split_at_too_far() {
+        let b = Bytes::from(vec![1, 2, 3, 4]);
+        b.split_at(5);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Bytes used after its backing allocation was freed by its owner")]
+    fn test_bytes_dangling_clone() {
+        let b1 = Bytes::from(vec![1, 2, 3]);
+        let b2 = b1.clone();
+        let b3 = b2.truncated(1);
+        drop(b1);
+        assert_eq!(b3.as_ref(), [1]);
+    }
+
     #[test]
     fn test_byteutil() {
         let mut v: Vec<u8> = vec![];
@@ -291,6 +1024,21 @@ mod tests {
         assert_eq!(res, val);
     }
 
+    #[test]
+    fn test_bytereader() {
+        let mut v: Vec<u8> = vec![];
+        ByteUtil::put_u16(&mut v, 7);
+        ByteUtil::put_u32(&mut v, 12345);
+        ByteUtil::put_u64(&mut v, 1234567899);
+
+        let mut r = v.as_slice();
+        assert_eq!(r.read_u16(), Some(7));
+        assert_eq!(r.read_u32(), Some(12345));
+        assert_eq!(r.remaining(), 8);
+        assert_eq!(r.read_u64(), Some(1234567899));
+        assert_eq!(r.read_u16(), None);
+    }
+
     #[test]
     fn test_byteutil_u64() {
         let mut v: Vec<u8> = vec![];
@@ -304,4 +1052,107 @@ mod tests {
         assert_eq!(res1, val1);
         assert_eq!(res, val);
     }
+
+    #[test]
+    fn test_byteutil_lifo() {
+        let mut v: Vec<u8> = vec![0xaa];
+        ByteUtil::put_u16(&mut v, 0x0102);
+        ByteUtil::put_u64(&mut v, 0x0304_0506_0708_090a);
+        ByteUtil::put_u32(&mut v, 0x0b0c_0d0e);
+        ByteUtil::put_u16(&mut v, 0x0f10);
+        assert_eq!(v[1..], (1..=16).collect::<Vec<u8>>());
+
+        assert_eq!(ByteUtil::get_u16(&mut v), Some(0x0f10));
+        assert_eq!(ByteUtil::get_u32(&mut v), Some(0x0b0c_0d0e));
+        assert_eq!(ByteUtil::get_u64(&mut v), Some(0x0304_0506_0708_090a));
+        assert_eq!(ByteUtil::get_u16(&mut v), Some(0x0102));
+        // Too short to pop, and left untouched.
+        assert_eq!(ByteUtil::get_u16(&mut v), None);
+        assert_eq!(v, [0xaa]);
+    }
+
+    #[test]
+    fn test_byteutil_reader_order() {
+        let mut v: Vec<u8> = vec![];
+        ByteUtil::put_u16(&mut v, 1);
+        ByteUtil::put_u16(&mut v, 2);
+        ByteUtil::put_u64(&mut v, 3);
+
+        // Written order comes back through a reader...
+        let mut r = v.as_slice();
+        assert_eq!(r.read_u16(), Some(1));
+        assert_eq!(r.read_u16(), Some(2));
+        assert_eq!(r.read_u64(), Some(3));
+
+        // ...and reversed through the stack.
+        assert_eq!(ByteUtil::get_u64(&mut v), Some(3));
+        assert_eq!(ByteUtil::get_u16(&mut v), Some(2));
+        assert_eq!(ByteUtil::get_u16(&mut v), Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "custom-alloc")]
+    fn test_bytes_custom_alloc() {
+        static ALLOC: CountingAlloc = CountingAlloc {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        };
+
+        let hello = b"hello, this is too long to inline";
+        let world = b"world, this is too long to inline";
+        let b1 = Bytes::copy_from_slice_in(hello, &ALLOC);
+        let b2 = Bytes::copy_from_slice_in(world, &ALLOC);
+        let empty = Bytes::copy_from_slice_in(b"", &ALLOC);
+        let b3 = b1.clone();
+        assert_eq!(b1.as_ref(), hello);
+        assert_eq!(b2.as_ref(), world);
+        assert!(empty.as_ref().is_empty());
+        assert_eq!(ALLOC.allocs.load(Ordering::SeqCst), 2);
+
+        // A deep copy comes from the same allocator, and `into_vec` copies out
+        // of it instead of handing its buffer to a `Vec`.
+        let copy = b3.deep_copy();
+        assert_eq!(ALLOC.allocs.load(Ordering::SeqCst), 3);
+        assert_eq!(copy.into_vec(), hello);
+        assert_eq!(ALLOC.frees.load(Ordering::SeqCst), 1);
+
+        drop(b3);
+        assert_eq!(ALLOC.frees.load(Ordering::SeqCst), 1);
+        drop(b1);
+        drop(b2);
+        drop(empty);
+        assert_eq!(ALLOC.frees.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "custom-alloc")]
+    fn test_bytes_inline_no_alloc() {
+        static ALLOC: CountingAlloc = CountingAlloc {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        };
+
+        let data: Vec<u8> = (0..=INLINE_CAP as u8).collect();
+        let inline = Bytes::copy_from_slice_in(&data[..INLINE_CAP], &ALLOC);
+        assert_eq!(ALLOC.allocs.load(Ordering::SeqCst), 0);
+        let heap = Bytes::copy_from_slice_in(&data, &ALLOC);
+        assert_eq!(ALLOC.allocs.load(Ordering::SeqCst), 1);
+        assert_eq!(inline.as_ref(), &data[..INLINE_CAP]);
+        assert_eq!(heap.as_ref(), &data[..]);
+
+        // Many tiny values, and copies of the inline one, never reach the
+        // allocator.
+        let tiny: Vec<_> = (0..10_000u64)
+            .map(|i| Bytes::copy_from_slice_in(&i.to_be_bytes(), &ALLOC))
+            .collect();
+        assert_eq!(tiny[42].as_ref(), 42u64.to_be_bytes());
+        let copy = inline.deep_copy();
+        assert_eq!(copy, inline);
+        assert_eq!(ALLOC.allocs.load(Ordering::SeqCst), 1);
+
+        drop((tiny, copy, inline));
+        assert_eq!(ALLOC.frees.load(Ordering::SeqCst), 0);
+        drop(heap);
+        assert_eq!(ALLOC.frees.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,374 @@
+use anyhow::Result;
+
+use crate::{
+    byte::Bytes,
+    key::{KeyBytes, KeySlice},
+};
+
+/// The common interface of every iterator over key-value pairs, so that
+/// iterators over different sources can be composed generically.
+pub trait StorageIterator {
+    /// Get the current key.
+    fn key(&self) -> KeySlice<'_>;
+
+    /// Get the current value.
+    fn value(&self) -> &[u8];
+
+    /// Check if the current iterator is valid.
+    fn is_valid(&self) -> bool;
+
+    /// Move to the next position.
+    fn next(&mut self) -> Result<()>;
+
+    /// Get a copy of the current key that isn't tied to the iterator, for
+    /// collecting results. Prefer [`StorageIterator::key`] in hot loops.
+    fn key_bytes(&self) -> KeyBytes {
+        self.key().to_key_bytes()
+    }
+
+    /// Get a copy of the current value that isn't tied to the iterator, for
+    /// collecting results. Prefer [`StorageIterator::value`] in hot loops.
+    fn value_bytes(&self) -> Bytes {
+        Bytes::copy_from_slice(self.value())
+    }
+
+    /// Skip every entry whose key (without the version) doesn't satisfy
+    /// `predicate`. Values are never read, and since the predicate only sees
+    /// the key, all versions of a key are kept or skipped together.
+    fn filter_keys<F>(self, predicate: F) -> Result<FilterKeysIterator<Self, F>>
+    where
+        Self: Sized,
+        F: FnMut(&[u8]) -> bool,
+    {
+        FilterKeysIterator::new(self, predicate)
+    }
+
+    /// Hide every version newer than `read_ts`, and narrow what's left
+    /// further according to `options`.
+    ///
+    /// There are no range tombstones: a range deletion would have to be
+    /// applied to the inner iterator first, since this only knows about
+    /// per-key tombstones (empty values).
+    fn versions_as_of(self, read_ts: u64, options: AsOfOptions) -> Result<AsOfIterator<Self>>
+    where
+        Self: Sized,
+    {
+        AsOfIterator::new(self, read_ts, options)
+    }
+}
+
+/// What [`StorageIterator::versions_as_of`] yields of the versions at or
+/// below the read ts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsOfOptions {
+    /// Yield only the newest version of each key, rather than all of them.
+    pub collapse: bool,
+    /// Skip tombstones. Together with `collapse`, a key whose newest version
+    /// is a tombstone is skipped entirely, since it shadows the older ones;
+    /// without it, only the tombstones themselves are skipped.
+    pub hide_tombstones: bool,
+}
+
+impl Default for AsOfOptions {
+    /// The live value of each key as of the read ts.
+    fn default() -> Self {
+        Self {
+            collapse: true,
+            hide_tombstones: true,
+        }
+    }
+}
+
+/// An iterator over the versions visible at a read ts, created by
+/// [`StorageIterator::versions_as_of`].
+pub struct AsOfIterator<I> {
+    iter: I,
+    read_ts: u64,
+    options: AsOfOptions,
+    // The last key whose newest visible version was reached, when collapsing.
+    prev_key: Option<Vec<u8>>,
+}
+
+impl<I: StorageIterator> AsOfIterator<I> {
+    fn new(iter: I, read_ts: u64, options: AsOfOptions) -> Result<Self> {
+        let mut iter = Self {
+            iter,
+            read_ts,
+            options,
+            prev_key: None,
+        };
+        iter.skip_invisible()?;
+        Ok(iter)
+    }
+
+    fn skip_invisible(&mut self) -> Result<()> {
+        while self.iter.is_valid() {
+            let key = self.iter.key();
+            if key.version() > self.read_ts {
+                self.iter.next()?;
+                continue;
+            }
+            if self.options.collapse {
+                if self.prev_key.as_deref() == Some(key.key_ref()) {
+                    self.iter.next()?;
+                    continue;
+                }
+                self.prev_key = Some(key.key_ref().to_vec());
+            }
+            if self.options.hide_tombstones && self.iter.value().is_empty() {
+                self.iter.next()?;
+                continue;
+            }
+            break;
+        }
+        Ok(())
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for AsOfIterator<I> {
+    fn key(&self) -> KeySlice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_invisible()
+    }
+}
+
+/// An iterator that yields only the entries whose key satisfies a predicate,
+/// created by [`StorageIterator::filter_keys`].
+pub struct FilterKeysIterator<I, F> {
+    iter: I,
+    predicate: F,
+}
+
+impl<I: StorageIterator, F: FnMut(&[u8]) -> bool> FilterKeysIterator<I, F> {
+    fn new(iter: I, predicate: F) -> Result<Self> {
+        let mut iter = Self { iter, predicate };
+        iter.skip_unmatched()?;
+        Ok(iter)
+    }
+
+    fn skip_unmatched(&mut self) -> Result<()> {
+        while self.iter.is_valid() && !(self.predicate)(self.iter.key().key_ref()) {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I: StorageIterator, F: FnMut(&[u8]) -> bool> StorageIterator for FilterKeysIterator<I, F> {
+    fn key(&self) -> KeySlice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_unmatched()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use crate::{key::Key, mem_table::MemTable};
+
+    use super::*;
+
+    struct VecIterator {
+        data: Vec<(&'static [u8], &'static [u8])>,
+        idx: usize,
+    }
+
+    impl StorageIterator for VecIterator {
+        fn key(&self) -> KeySlice<'_> {
+            Key::for_testing_from_slice_no_ts(self.data[self.idx].0)
+        }
+
+        fn value(&self) -> &[u8] {
+            self.data[self.idx].1
+        }
+
+        fn is_valid(&self) -> bool {
+            self.idx < self.data.len()
+        }
+
+        fn next(&mut self) -> Result<()> {
+            self.idx += 1;
+            Ok(())
+        }
+    }
+
+    fn count_entries(mut iter: impl StorageIterator) -> Result<usize> {
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next()?;
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_generic_over_iterators() {
+        let memtable = MemTable::new(0);
+        for key in [b"key1", b"key2", b"key3"] {
+            memtable.put(Key::from_slice(key, 0), b"value").unwrap();
+        }
+        let iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(count_entries(iter).unwrap(), 3);
+
+        let iter = VecIterator {
+            data: vec![(b"a", b"1"), (b"b", b"2")],
+            idx: 0,
+        };
+        assert_eq!(count_entries(iter).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let memtable = MemTable::new(0);
+        for (key, version) in [
+            (&b"a/1"[..], 1),
+            (b"b/1", 1),
+            (b"b/1", 2),
+            (b"b/2", 1),
+            (b"c/1", 1),
+            (b"b", 1),
+        ] {
+            memtable
+                .put(Key::from_slice(key, version), b"value")
+                .unwrap();
+        }
+
+        let mut iter = memtable
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .filter_keys(|key| key.starts_with(b"b/"))
+            .unwrap();
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push((iter.key().key_ref().to_vec(), iter.key().version()));
+            iter.next().unwrap();
+        }
+        assert_eq!(
+            keys,
+            vec![
+                (b"b/1".to_vec(), 2),
+                (b"b/1".to_vec(), 1),
+                (b"b/2".to_vec(), 1)
+            ]
+        );
+
+        let iter = memtable
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .filter_keys(|_| false)
+            .unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn test_collect_owned() {
+        let memtable = MemTable::new(0);
+        let value = vec![b'v'; 100];
+        for key in [b"key1", b"key2", b"key3"] {
+            memtable.put(Key::from_slice(key, 1), &value).unwrap();
+        }
+
+        let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+        let mut entries: Vec<(KeyBytes, Bytes)> = vec![];
+        while iter.is_valid() {
+            entries.push((iter.key_bytes(), iter.value_bytes()));
+            iter.next().unwrap();
+        }
+        drop(iter);
+        drop(memtable);
+
+        assert_eq!(entries.len(), 3);
+        for ((key, entry_value), expected) in entries.iter().zip([b"key1", b"key2", b"key3"]) {
+            assert_eq!(key.into_inner(), expected);
+            assert_eq!(key.version(), 1);
+            assert_eq!(entry_value.as_ref(), value);
+        }
+    }
+
+    fn collect_as_of(memtable: &MemTable, read_ts: u64, options: AsOfOptions) -> Vec<String> {
+        let mut iter = memtable
+            .scan_as_of(Bound::Unbounded, Bound::Unbounded, read_ts, options)
+            .unwrap();
+        let mut res = vec![];
+        while iter.is_valid() {
+            res.push(format!(
+                "{}@{}={}",
+                String::from_utf8_lossy(iter.key().key_ref()),
+                iter.key().version(),
+                String::from_utf8_lossy(iter.value())
+            ));
+            iter.next().unwrap();
+        }
+        res
+    }
+
+    #[test]
+    fn test_as_of() {
+        let memtable = MemTable::new(0);
+        for (key, version, value) in [
+            // Deleted at 2, revived at 4.
+            (&b"a"[..], 1, &b"a1"[..]),
+            (b"a", 2, b""),
+            (b"a", 4, b"a4"),
+            // Only written after the read ts.
+            (b"b", 5, b"b5"),
+            // Live, with an older version.
+            (b"c", 1, b"c1"),
+            (b"c", 3, b"c3"),
+        ] {
+            memtable.put(Key::from_slice(key, version), value).unwrap();
+        }
+
+        let options = AsOfOptions::default();
+        assert_eq!(collect_as_of(&memtable, 3, options), ["c@3=c3"]);
+        assert_eq!(collect_as_of(&memtable, 4, options), ["a@4=a4", "c@3=c3"]);
+        assert_eq!(collect_as_of(&memtable, 1, options), ["a@1=a1", "c@1=c1"]);
+
+        let options = AsOfOptions {
+            collapse: true,
+            hide_tombstones: false,
+        };
+        assert_eq!(collect_as_of(&memtable, 3, options), ["a@2=", "c@3=c3"]);
+
+        let options = AsOfOptions {
+            collapse: false,
+            hide_tombstones: true,
+        };
+        assert_eq!(
+            collect_as_of(&memtable, 3, options),
+            ["a@1=a1", "c@3=c3", "c@1=c1"]
+        );
+
+        let options = AsOfOptions {
+            collapse: false,
+            hide_tombstones: false,
+        };
+        assert_eq!(
+            collect_as_of(&memtable, 3, options),
+            ["a@2=", "a@1=a1", "c@3=c3", "c@1=c1"]
+        );
+    }
+}
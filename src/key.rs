@@ -1,8 +1,10 @@
 use std::cmp::Reverse;
 
-use crate::byte::Bytes;
+use crate::byte::{ByteUtil, Bytes};
 
 /// The key contains the actual key value's u8 array format and the version number.
+///
+/// An empty key value is valid, and sorts before every non-empty one.
 pub struct Key<T: AsRef<[u8]>>(T, u64);
 
 // Use Bytes as the inner struct.
@@ -13,10 +15,39 @@ pub type KeySlice<'a> = Key<&'a [u8]>;
 
 const DEFAULT_VERSION: u64 = 0;
 
+// Ends the raw key in an encoded key, see `Key::encode`.
+const KEY_TERMINATOR: &[u8] = &[0, 0];
+
 impl KeyBytes {
     pub fn new(bytes: Bytes, version: u64) -> Self {
         Self(bytes, version)
     }
+
+    /// Decode a key written by [`Key::encode`].
+    ///
+    /// Panics if `data` is not an encoded key.
+    pub fn decode(data: &[u8]) -> Self {
+        let (escaped, version) = data
+            .split_last_chunk::<8>()
+            .expect("encoded key is missing its version");
+        let version = !u64::from_be_bytes(*version);
+        let escaped = escaped
+            .strip_suffix(KEY_TERMINATOR)
+            .expect("encoded key is missing its terminator");
+        if !escaped.contains(&0) {
+            return Self(Bytes::copy_from_slice(escaped), version);
+        }
+
+        let mut key = Vec::with_capacity(escaped.len());
+        let mut bytes = escaped.iter();
+        while let Some(&b) = bytes.next() {
+            key.push(b);
+            if b == 0 {
+                assert_eq!(bytes.next(), Some(&0xff), "bad escape in encoded key");
+            }
+        }
+        Self(Bytes::copy_from_slice(&key), version)
+    }
 }
 
 impl<T: AsRef<[u8]>> Key<T> {
@@ -39,6 +70,25 @@ impl<T: AsRef<[u8]>> Key<T> {
     pub fn version(&self) -> u64 {
         self.1
     }
+
+    /// Encode the key to the on-disk format: the raw key with each 0x00
+    /// escaped as 0x00 0xff, then a 0x00 0x00 terminator, then the
+    /// bitwise-inverted version in big-endian.
+    ///
+    /// Encoded keys compare byte-wise exactly like `Key`'s `Ord`. The
+    /// terminator sorts before any escaped byte, so a key sorts before every
+    /// key it's a proper prefix of, and inverting the version makes newer
+    /// versions compare smaller.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        for &b in self.0.as_ref() {
+            buf.push(b);
+            if b == 0 {
+                buf.push(0xff);
+            }
+        }
+        buf.extend_from_slice(KEY_TERMINATOR);
+        buf.put_u64(!self.1);
+    }
 }
 
 impl<'a> Key<&'a [u8]> {
@@ -59,8 +109,7 @@ impl<'a> Key<&'a [u8]> {
     }
 
     pub fn to_key_bytes(&self) -> KeyBytes {
-        let bytes = Bytes::from(self.0.to_vec());
-        Key(bytes, self.1)
+        Key(Bytes::copy_from_slice(self.0), self.1)
     }
 }
 
@@ -119,7 +168,7 @@ mod tests {
 
     use crate::byte::Bytes;
 
-    use super::Key;
+    use super::{Key, KeyBytes};
     #[test]
     fn test_key_order() {
         let vals = vec!["1", "2", "3", "4"];
@@ -133,4 +182,78 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_empty_key_order() {
+        let empty = Key::from_slice(b"", 0);
+        assert!(empty < Key::from_slice(b"\0", u64::MAX));
+        assert!(empty < Key::from_slice(b"a", 0));
+        assert!(Key::from_slice(b"", 1) < empty);
+    }
+
+    #[test]
+    fn test_key_to_key_bytes() {
+        let key = Key::from_slice(b"key1", 42).to_key_bytes();
+        assert_eq!(key.into_inner(), b"key1");
+        assert_eq!(key.version(), 42);
+
+        let key = Key::from_slice(b"", 7).to_key_bytes();
+        assert!(key.is_empty());
+        assert_eq!(key.version(), 7);
+    }
+
+    #[test]
+    fn test_key_encode_decode() {
+        let key = Key::from_slice(b"key1", 42);
+        let mut buf = vec![];
+        key.encode(&mut buf);
+        assert_eq!(buf.len(), key.raw_len() + 2);
+
+        let decoded = KeyBytes::decode(&buf);
+        assert_eq!(decoded.into_inner(), b"key1");
+        assert_eq!(decoded.version(), 42);
+
+        let mut buf = vec![];
+        Key::from_slice(b"", 7).encode(&mut buf);
+        let decoded = KeyBytes::decode(&buf);
+        assert!(decoded.is_empty());
+        assert_eq!(decoded.version(), 7);
+
+        for raw in [&b"\0"[..], b"a\0\0b", b"\0\xff\0", b"\xff"] {
+            let mut buf = vec![];
+            Key::from_slice(raw, u64::MAX).encode(&mut buf);
+            let decoded = KeyBytes::decode(&buf);
+            assert_eq!(decoded.into_inner(), raw);
+            assert_eq!(decoded.version(), u64::MAX);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "encoded key is missing its terminator")]
+    fn test_key_decode_unterminated() {
+        KeyBytes::decode(b"key1\0\0\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn test_key_encode_order() {
+        let vals: [&[u8]; 11] = [
+            b"", b"\0", b"\0\0", b"\x01", b"a", b"a\0", b"a\0b", b"aa", b"ab", b"b1", b"ba",
+        ];
+        let versions = [0, 1, 255, 256, u64::MAX];
+        let mut keys = vec![];
+        for val in vals {
+            for version in versions {
+                keys.push(Key::from_slice(val, version));
+            }
+        }
+
+        for a in &keys {
+            for b in &keys {
+                let (mut ea, mut eb) = (vec![], vec![]);
+                a.encode(&mut ea);
+                b.encode(&mut eb);
+                assert_eq!(ea.cmp(&eb), a.cmp(b), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
 }
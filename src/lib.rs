@@ -1,7 +1,10 @@
 pub mod block;
 pub mod byte;
+pub mod iterators;
 pub mod key;
 pub mod lsm_storage;
 pub mod mem_table;
 pub mod table;
+#[cfg(test)]
+mod testing;
 pub mod wal;
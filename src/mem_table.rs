@@ -1,47 +1,310 @@
 use std::{
-    path::Path,
-    sync::{atomic::AtomicUsize, Arc},
+    collections::{BTreeMap, HashSet},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock},
 };
 
-use crossbeam_skiplist::SkipMap;
+use crossbeam_skiplist::{
+    map::{Entry, Range},
+    SkipMap,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::{
     byte::Bytes,
-    key::{KeyBytes, KeySlice},
-    wal::Wal,
+    iterators::{AsOfIterator, AsOfOptions, StorageIterator},
+    key::{Key, KeyBytes, KeySlice},
+    wal::{ChecksumMode, RecoverOptions, Wal, WalOptions},
 };
 
+/// The result of looking up a key in a single memtable.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// The key isn't in this memtable, older sources may still have it.
+    Missing,
+    /// The key was deleted, which is recorded by putting an empty value.
+    /// Older sources must not be searched.
+    Tombstone,
+    /// The key's value.
+    Value(Bytes),
+}
+
+/// The estimated memory each memtable entry costs on top of its key and value
+/// bytes: the `KeyBytes` and `Bytes` headers, plus the skiplist node's
+/// reference count, height and (on average) two tower pointers.
+pub const ENTRY_OVERHEAD: usize = std::mem::size_of::<KeyBytes>()
+    + std::mem::size_of::<Bytes>()
+    + 3 * std::mem::size_of::<usize>();
+
+/// Options for [`MemTable::put_batch_with_options`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// Sync the WAL to disk before returning, so the write survives a crash.
+    /// Without it, the write may sit in a buffer until a later sync.
+    pub sync: bool,
+}
+
+/// The largest keys and values a memtable accepts, checked by every put.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Default for SizeLimits {
+    /// The largest sizes the WAL can frame, which stores key and value lengths
+    /// as u16.
+    fn default() -> Self {
+        Self {
+            max_key_size: u16::MAX as usize,
+            max_value_size: u16::MAX as usize,
+        }
+    }
+}
+
+impl SizeLimits {
+    /// Check that no limit is larger than the default, which is the most the
+    /// WAL can store.
+    pub fn validate(&self) -> Result<()> {
+        let max = Self::default();
+        if self.max_key_size > max.max_key_size || self.max_value_size > max.max_value_size {
+            bail!("size limits {:?} exceed {:?}", self, max);
+        }
+        Ok(())
+    }
+}
+
+/// Options for a memtable with a WAL, see [`MemTable::new_with_wal_options`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemTableOptions {
+    pub size_limits: SizeLimits,
+    pub wal: WalOptions,
+}
+
+impl MemTableOptions {
+    /// Check that the limits and the WAL options are in range.
+    pub fn validate(&self) -> Result<()> {
+        self.size_limits.validate()?;
+        self.wal.validate()
+    }
+}
+
+/// Keeps a derived index in step with a memtable's writes, see
+/// [`MemTable::with_index_hook`].
+pub trait IndexHook: Send + Sync {
+    /// Called for every put of a non-empty value.
+    fn on_put(&self, key: KeySlice, value: &[u8]) -> Result<()>;
+
+    /// Called for every delete, which is a put of an empty value.
+    fn on_delete(&self, key: KeySlice) -> Result<()>;
+}
+
+/// Which sorted map a memtable keeps its entries in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemTableBackend {
+    /// A lock-free skiplist. Writers and readers never block each other, at
+    /// the cost of a few pointers per entry.
+    #[default]
+    SkipList,
+    /// A `BTreeMap` behind a read-write lock. More compact, but every insert
+    /// takes the write lock, so concurrent writes are serialized and block
+    /// readers, and iterators copy each entry out.
+    BTree,
+}
+
+/// The sorted map behind a memtable.
+pub trait MemTableMap: Send + Sync {
+    fn get(&self, key: &KeyBytes) -> Option<Bytes>;
+
+    fn insert(&self, key: KeyBytes, value: Bytes);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get an iterator over a range of keys, which keeps the map alive.
+    fn scan(
+        self: Arc<Self>,
+        lower: Bound<KeyBytes>,
+        upper: Bound<KeyBytes>,
+    ) -> Box<dyn StorageIterator>;
+}
+
 pub struct MemTable {
-    pub(crate) map: Arc<SkipMap<KeyBytes, Bytes>>,
+    pub(crate) map: Arc<dyn MemTableMap>,
     wal: Option<Wal>,
     id: usize,
+    limits: SizeLimits,
+    index_hook: Option<Arc<dyn IndexHook>>,
     approximate_size: Arc<AtomicUsize>,
+    // The keys that a `put_if_absent` found absent and is still writing.
+    creating: Mutex<HashSet<Vec<u8>>>,
 }
 
 impl MemTable {
     pub fn new(id: usize) -> Self {
         Self {
             id,
-            map: Arc::new(SkipMap::new()),
+            map: Arc::new(SkipMap::<KeyBytes, Bytes>::new()),
             wal: None,
+            limits: SizeLimits::default(),
+            index_hook: None,
             approximate_size: Arc::new(AtomicUsize::new(0)),
+            creating: Mutex::new(HashSet::new()),
         }
     }
 
     /// Create a new mem-table with WAL
     pub fn new_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        Self::new_with_wal_options(id, path, MemTableOptions::default())
+    }
+
+    /// Like [`MemTable::new_with_wal`], with the given limits and WAL options,
+    /// which are checked before the WAL is created.
+    pub fn new_with_wal_options(
+        id: usize,
+        path: impl AsRef<Path>,
+        options: MemTableOptions,
+    ) -> Result<Self> {
+        options.validate()?;
         Ok(Self {
             id,
-            map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::new(path.as_ref())?),
+            map: Arc::new(SkipMap::<KeyBytes, Bytes>::new()),
+            wal: Some(Wal::new_with_options(path.as_ref(), id, options.wal)?),
+            limits: options.size_limits,
+            index_hook: None,
             approximate_size: Arc::new(AtomicUsize::new(0)),
+            creating: Mutex::new(HashSet::new()),
         })
     }
 
-    /// Get a value by key.
+    /// Recover a memtable from its WAL, taking the id from the WAL's header.
+    pub fn recover_from_wal(path: impl AsRef<Path>, options: RecoverOptions) -> Result<Self> {
+        Self::recover_from_wal_with_options(path, options, MemTableOptions::default())
+    }
+
+    /// Like [`MemTable::recover_from_wal`], with the given limits and WAL
+    /// options for the writes that follow. They are checked before the WAL is
+    /// read, but entries already in it are replayed whatever their size.
+    pub fn recover_from_wal_with_options(
+        path: impl AsRef<Path>,
+        options: RecoverOptions,
+        memtable_options: MemTableOptions,
+    ) -> Result<Self> {
+        memtable_options.validate()?;
+        let path = path.as_ref();
+        let map = SkipMap::new();
+        let wal = Wal::recover_with_options(path, &map, options, memtable_options.wal)?;
+        let Some(header) = wal.header() else {
+            bail!(
+                "{}: WAL header is torn, so the memtable id is unknown",
+                path.display()
+            );
+        };
+        let approximate_size = map
+            .iter()
+            .map(|e| e.key().raw_len() + e.value().as_ref().len())
+            .sum();
+        Ok(Self {
+            id: header.memtable_id,
+            map: Arc::new(map),
+            wal: Some(wal),
+            limits: memtable_options.size_limits,
+            index_hook: None,
+            approximate_size: Arc::new(AtomicUsize::new(approximate_size)),
+            creating: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Recover a memtable from each of the WALs at `paths`, returned in id
+    /// order, oldest first. The headers decide the order, not the file names.
+    ///
+    /// In lenient mode, a WAL whose header was torn by a crash while it was
+    /// being created holds no writes, and is skipped.
+    pub fn recover_all(
+        paths: impl IntoIterator<Item = PathBuf>,
+        options: RecoverOptions,
+    ) -> Result<Vec<Self>> {
+        Self::recover_all_with_options(paths, options, MemTableOptions::default())
+    }
+
+    /// Like [`MemTable::recover_all`], see
+    /// [`MemTable::recover_from_wal_with_options`].
+    pub fn recover_all_with_options(
+        paths: impl IntoIterator<Item = PathBuf>,
+        options: RecoverOptions,
+        memtable_options: MemTableOptions,
+    ) -> Result<Vec<Self>> {
+        memtable_options.validate()?;
+        let mut wals = vec![];
+        for path in paths {
+            let Some(header) = Wal::read_header(&path)? else {
+                match options.checksum_mode {
+                    ChecksumMode::Strict => bail!("{}: WAL header is torn", path.display()),
+                    ChecksumMode::Lenient => {
+                        log::warn!("{}: skipping a WAL with a torn header", path.display());
+                        continue;
+                    }
+                }
+            };
+            wals.push((header.memtable_id, header.created_at, path));
+        }
+        wals.sort();
+        wals.into_iter()
+            .map(|(_, _, path)| {
+                Self::recover_from_wal_with_options(path, options, memtable_options)
+            })
+            .collect()
+    }
+
+    /// Keep the entries in `backend` rather than the default skiplist. Panics
+    /// if the memtable isn't empty.
+    pub fn with_backend(mut self, backend: MemTableBackend) -> Self {
+        assert!(
+            self.is_empty(),
+            "can't change the backend of a non-empty memtable"
+        );
+        self.map = match backend {
+            MemTableBackend::SkipList => Arc::new(SkipMap::<KeyBytes, Bytes>::new()),
+            MemTableBackend::BTree => Arc::new(RwLock::new(BTreeMap::<KeyBytes, Bytes>::new())),
+        };
+        self
+    }
+
+    /// Call `hook` for every entry written, in order, before the write reaches
+    /// the WAL. An error from the hook aborts the write, though the hook has
+    /// then already seen the entries before the failing one. A write that
+    /// fails after the hook, such as on a WAL I/O error, is not reported to it.
+    pub fn with_index_hook(mut self, hook: Arc<dyn IndexHook>) -> Self {
+        self.index_hook = Some(hook);
+        self
+    }
+
+    /// Lower the size limits from their defaults. Panics if a limit is larger
+    /// than the default, which is the most the WAL can store.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        if let Err(err) = limits.validate() {
+            panic!("{}", err);
+        }
+        self.limits = limits;
+        self
+    }
+
+    /// Get a value by key. A deleted key is reported as `None`, use
+    /// [`MemTable::get_entry`] to tell it apart from a missing one.
     pub fn get(&self, key: KeySlice) -> Option<Bytes> {
+        match self.get_entry(key) {
+            Lookup::Value(value) => Some(value),
+            Lookup::Missing | Lookup::Tombstone => None,
+        }
+    }
+
+    /// Look up a key, telling a deleted key apart from a missing one.
+    pub fn get_entry(&self, key: KeySlice) -> Lookup {
         let key_bytes = KeyBytes::new(
             // SAFETY:
             // Here transfer the lifetime of &[u8] to 'static
@@ -52,7 +315,11 @@ impl MemTable {
             key.version(),
         );
 
-        self.map.get(&key_bytes).map(|e| e.value().clone())
+        match self.map.get(&key_bytes) {
+            None => Lookup::Missing,
+            Some(value) if value.as_ref().is_empty() => Lookup::Tombstone,
+            Some(value) => Lookup::Value(value),
+        }
     }
 
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
@@ -60,6 +327,59 @@ impl MemTable {
     }
 
     pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        self.put_batch_with_options(data, WriteOptions::default())
+    }
+
+    pub fn put_with_options(
+        &self,
+        key: KeySlice,
+        value: &[u8],
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.put_batch_with_options(&[(key, value)], options)
+    }
+
+    /// Put a batch, syncing the WAL first if `options.sync` is set. A memtable
+    /// without a WAL ignores it.
+    ///
+    /// Fails without writing anything if any key or value is over the
+    /// memtable's [`SizeLimits`].
+    pub fn put_batch_with_options(
+        &self,
+        data: &[(KeySlice, &[u8])],
+        options: WriteOptions,
+    ) -> Result<()> {
+        for (key, value) in data {
+            if key.key_len() > self.limits.max_key_size {
+                bail!(
+                    "key of {} bytes exceeds the limit of {} bytes",
+                    key.key_len(),
+                    self.limits.max_key_size
+                );
+            }
+            if value.len() > self.limits.max_value_size {
+                bail!(
+                    "value of {} bytes exceeds the limit of {} bytes",
+                    value.len(),
+                    self.limits.max_value_size
+                );
+            }
+        }
+        if let Some(ref hook) = self.index_hook {
+            for (key, value) in data {
+                if value.is_empty() {
+                    hook.on_delete(*key)?;
+                } else {
+                    hook.on_put(*key, value)?;
+                }
+            }
+        }
+        if let Some(ref wal) = self.wal {
+            wal.put_batch(data)?;
+            if options.sync {
+                wal.sync()?;
+            }
+        }
         let mut data_size = 0;
         for (key, value) in data {
             data_size += key.raw_len() + value.len();
@@ -67,13 +387,60 @@ impl MemTable {
         }
         self.approximate_size
             .fetch_add(data_size, std::sync::atomic::Ordering::Relaxed);
-        if let Some(ref _wal) = self.wal {
-            // TODO: add wal support.
-            // wal.put_batch(data)?;
-        }
         Ok(())
     }
 
+    /// Put a value only if the key is absent, returning whether it was
+    /// inserted. The key is absent if it has no version at all, or if its
+    /// newest version, whatever that is, is a tombstone.
+    ///
+    /// Of concurrent `put_if_absent` calls for the same key, only one inserts,
+    /// and the others return `false` even if its write then fails. Plain puts
+    /// aren't held back, though: one that lands between the check and the
+    /// insert isn't seen, so the key may end up with both values, at their
+    /// own versions.
+    ///
+    /// This only checks the memtable itself: uniqueness across the whole store
+    /// also needs a read of the older memtables and SSTables.
+    pub fn put_if_absent(&self, key: KeySlice, value: &[u8]) -> Result<bool> {
+        // Claim the key while checking it, but write without holding the
+        // lock, so that creating other keys isn't held up by this one's WAL
+        // write.
+        {
+            let mut creating = self.creating.lock().unwrap();
+            let newest = self.scan(
+                Bound::Included(Key::from_slice(key.key_ref(), u64::MAX)),
+                Bound::Included(Key::from_slice(key.key_ref(), 0)),
+            );
+            if creating.contains(key.key_ref()) || newest.is_valid() && !newest.value().is_empty() {
+                return Ok(false);
+            }
+            creating.insert(key.key_ref().to_vec());
+        }
+        let res = self.put(key, value);
+        self.creating.lock().unwrap().remove(key.key_ref());
+        res.map(|()| true)
+    }
+
+    pub fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        MemTableIterator(self.map.clone().scan(
+            lower.map(|key| key.to_key_bytes()),
+            upper.map(|key| key.to_key_bytes()),
+        ))
+    }
+
+    /// Get an iterator over a range of keys as of `read_ts`, see
+    /// [`StorageIterator::versions_as_of`].
+    pub fn scan_as_of(
+        &self,
+        lower: Bound<KeySlice>,
+        upper: Bound<KeySlice>,
+        read_ts: u64,
+        options: AsOfOptions,
+    ) -> Result<AsOfIterator<MemTableIterator>> {
+        self.scan(lower, upper).versions_as_of(read_ts, options)
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -83,17 +450,220 @@ impl MemTable {
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Like [`MemTable::approximate_size`], plus [`ENTRY_OVERHEAD`] for every
+    /// entry. This is the figure to compare against a memory budget when
+    /// deciding to freeze the memtable.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.approximate_size() + self.map.len() * ENTRY_OVERHEAD
+    }
+
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 }
 
+impl MemTableMap for SkipMap<KeyBytes, Bytes> {
+    fn get(&self, key: &KeyBytes) -> Option<Bytes> {
+        SkipMap::get(self, key).map(|e| e.value().clone())
+    }
+
+    fn insert(&self, key: KeyBytes, value: Bytes) {
+        SkipMap::insert(self, key, value);
+    }
+
+    fn len(&self) -> usize {
+        SkipMap::len(self)
+    }
+
+    fn scan(
+        self: Arc<Self>,
+        lower: Bound<KeyBytes>,
+        upper: Bound<KeyBytes>,
+    ) -> Box<dyn StorageIterator> {
+        let range = self.range((lower, upper));
+        // SAFETY:
+        // The range borrows from the skiplist, which the iterator keeps alive
+        // through its own `Arc` and drops only after the range and its entry.
+        let mut range =
+            unsafe { std::mem::transmute::<SkipMapRange<'_>, SkipMapRange<'static>>(range) };
+        let current = range.next();
+        Box::new(SkipMapIterator {
+            current,
+            range,
+            _map: self,
+        })
+    }
+}
+
+impl MemTableMap for RwLock<BTreeMap<KeyBytes, Bytes>> {
+    fn get(&self, key: &KeyBytes) -> Option<Bytes> {
+        self.read().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: KeyBytes, value: Bytes) {
+        self.write().unwrap().insert(key, value);
+    }
+
+    fn len(&self) -> usize {
+        self.read().unwrap().len()
+    }
+
+    fn scan(
+        self: Arc<Self>,
+        lower: Bound<KeyBytes>,
+        upper: Bound<KeyBytes>,
+    ) -> Box<dyn StorageIterator> {
+        let current = BTreeMapIterator::copy_first(&self.read().unwrap(), lower, &upper);
+        Box::new(BTreeMapIterator {
+            current,
+            upper,
+            map: self,
+        })
+    }
+}
+
+type SkipMapRange<'a> = Range<'a, KeyBytes, (Bound<KeyBytes>, Bound<KeyBytes>), KeyBytes, Bytes>;
+
+struct SkipMapIterator {
+    // Fields are dropped in order, so the borrowed entry and range go before
+    // the skiplist they point into.
+    current: Option<Entry<'static, KeyBytes, Bytes>>,
+    range: SkipMapRange<'static>,
+    _map: Arc<SkipMap<KeyBytes, Bytes>>,
+}
+
+impl StorageIterator for SkipMapIterator {
+    fn key(&self) -> KeySlice<'_> {
+        let key = self.current.as_ref().unwrap().key();
+        Key::from_slice(key.into_inner(), key.version())
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value().as_ref()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.current = self.range.next();
+        Ok(())
+    }
+}
+
+// The lock can't be held between calls, so each step copies the entry out and
+// seeks past it the next time.
+struct BTreeMapIterator {
+    current: Option<(KeyBytes, Bytes)>,
+    upper: Bound<KeyBytes>,
+    map: Arc<RwLock<BTreeMap<KeyBytes, Bytes>>>,
+}
+
+impl BTreeMapIterator {
+    // The only interior mutability in `Bytes` is its debug-only liveness
+    // flag, which doesn't take part in ordering.
+    #[allow(clippy::mutable_key_type)]
+    fn copy_first(
+        map: &BTreeMap<KeyBytes, Bytes>,
+        lower: Bound<KeyBytes>,
+        upper: &Bound<KeyBytes>,
+    ) -> Option<(KeyBytes, Bytes)> {
+        // `range` panics on an empty range, which the other backend yields
+        // nothing for.
+        let empty = match (&lower, upper) {
+            (Bound::Included(l), Bound::Included(u)) => l > u,
+            (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+                l >= u
+            }
+            _ => false,
+        };
+        if empty {
+            return None;
+        }
+        map.range::<KeyBytes, _>((lower.as_ref(), upper.as_ref()))
+            .next()
+            .map(|(key, value)| {
+                (
+                    Key::from_slice(key.into_inner(), key.version()).to_key_bytes(),
+                    Bytes::copy_from_slice(value.as_ref()),
+                )
+            })
+    }
+}
+
+impl StorageIterator for BTreeMapIterator {
+    fn key(&self) -> KeySlice<'_> {
+        let key = &self.current.as_ref().unwrap().0;
+        Key::from_slice(key.into_inner(), key.version())
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().1.as_ref()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if let Some((key, _)) = self.current.take() {
+            self.current =
+                Self::copy_first(&self.map.read().unwrap(), Bound::Excluded(key), &self.upper);
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over a range of a memtable.
+pub struct MemTableIterator(Box<dyn StorageIterator>);
+
+impl StorageIterator for MemTableIterator {
+    fn key(&self) -> KeySlice<'_> {
+        self.0.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.0.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.0.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.0.next()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::key::Key;
+    use tempfile::tempdir;
+
+    use crate::{
+        key::Key,
+        testing::{Oracle, Rng},
+    };
 
     use super::*;
 
+    #[test]
+    fn test_memtable_matches_oracle() {
+        for (seed, backend) in (1..=16).flat_map(|seed| {
+            [MemTableBackend::SkipList, MemTableBackend::BTree].map(|backend| (seed, backend))
+        }) {
+            let mut rng = Rng(seed);
+            let oracle = Oracle::random(&mut rng, 20, 200);
+            let memtable = MemTable::new(0).with_backend(backend);
+            for (key, value) in oracle.entries() {
+                memtable.put(key, value).unwrap();
+            }
+            if let Err(err) = oracle.check(&memtable, &mut rng) {
+                panic!("seed {} {:?}: {}", seed, backend, err);
+            }
+        }
+    }
+
     #[test]
     fn test_memtable_read_write() {
         let memtable = MemTable::new(0);
@@ -135,4 +705,520 @@ mod tests {
             assert_eq!(&memtable.get(key).unwrap().as_ref(), values[i]);
         }
     }
+
+    #[test]
+    fn test_memtable_empty_key() {
+        let memtable = MemTable::new(0);
+        memtable.put(Key::from_slice(b"a", 0), b"value2").unwrap();
+        memtable.put(Key::from_slice(b"", 0), b"value1").unwrap();
+        assert_eq!(
+            memtable.get(Key::from_slice(b"", 0)).unwrap().as_ref(),
+            b"value1"
+        );
+
+        let mut iter = memtable.scan(
+            Bound::Included(Key::from_slice(b"", u64::MAX)),
+            Bound::Included(Key::from_slice(b"a", 0)),
+        );
+        assert_eq!(iter.key().key_ref(), b"");
+        assert_eq!(iter.value(), b"value1");
+        iter.next().unwrap();
+        assert_eq!(iter.key().key_ref(), b"a");
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn test_memtable_memory_usage() {
+        let memtable = MemTable::new(0);
+        assert_eq!(memtable.approximate_memory_usage(), 0);
+
+        let mut raw_size = 0;
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let value = format!("value{}", i);
+            let key = Key::from_slice(key.as_bytes(), 0);
+            raw_size += key.raw_len() + value.len();
+            memtable.put(key, value.as_bytes()).unwrap();
+        }
+        assert_eq!(memtable.approximate_size(), raw_size);
+        assert_eq!(
+            memtable.approximate_memory_usage(),
+            raw_size + 100 * ENTRY_OVERHEAD
+        );
+    }
+
+    #[test]
+    fn test_memtable_put_if_absent() {
+        let memtable = MemTable::new(0);
+        let key = Key::from_slice(b"key1", 1);
+        assert!(memtable.put_if_absent(key, b"value1").unwrap());
+        assert!(!memtable.put_if_absent(key, b"value2").unwrap());
+        assert_eq!(memtable.get(key).unwrap().as_ref(), b"value1");
+
+        // The key exists at an older version, so a newer one isn't absent.
+        assert!(!memtable
+            .put_if_absent(Key::from_slice(b"key1", 2), b"value3")
+            .unwrap());
+        assert!(memtable.get(Key::from_slice(b"key1", 2)).is_none());
+
+        // A deleted key is absent, until it's put again.
+        memtable
+            .put(Key::from_slice(b"key2", 1), b"value1")
+            .unwrap();
+        memtable.put(Key::from_slice(b"key2", 2), b"").unwrap();
+        assert!(memtable
+            .put_if_absent(Key::from_slice(b"key2", 3), b"value3")
+            .unwrap());
+        assert!(!memtable
+            .put_if_absent(Key::from_slice(b"key2", 4), b"value4")
+            .unwrap());
+        assert_eq!(
+            memtable.get(Key::from_slice(b"key2", 3)).unwrap().as_ref(),
+            b"value3"
+        );
+
+        let memtable = Arc::new(MemTable::new(1));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    memtable
+                        .put_if_absent(Key::from_slice(b"key", 1), b"value")
+                        .unwrap()
+                })
+            })
+            .collect();
+        let inserted = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|inserted| *inserted)
+            .count();
+        assert_eq!(inserted, 1);
+    }
+
+    /// Holds up the put of one key until it's told to go on.
+    struct BlockingHook {
+        key: &'static [u8],
+        go: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl IndexHook for BlockingHook {
+        fn on_put(&self, key: KeySlice, _value: &[u8]) -> Result<()> {
+            if key.key_ref() == self.key {
+                let go = self.go.lock().unwrap();
+                go.recv_timeout(std::time::Duration::from_secs(10))?;
+            }
+            Ok(())
+        }
+
+        fn on_delete(&self, _key: KeySlice) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_memtable_put_if_absent_concurrent_keys() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let hook = Arc::new(BlockingHook {
+            key: b"key1",
+            go: Mutex::new(rx),
+        });
+        let memtable = Arc::new(MemTable::new(0).with_index_hook(hook));
+        let handle = {
+            let memtable = memtable.clone();
+            std::thread::spawn(move || {
+                memtable
+                    .put_if_absent(Key::from_slice(b"key1", 1), b"value1")
+                    .unwrap()
+            })
+        };
+        while memtable.creating.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+
+        // While key1 is being written, other keys can be created, and key1
+        // can't be created twice.
+        assert!(memtable
+            .put_if_absent(Key::from_slice(b"key2", 1), b"value2")
+            .unwrap());
+        assert!(!memtable
+            .put_if_absent(Key::from_slice(b"key1", 2), b"value3")
+            .unwrap());
+        tx.send(()).unwrap();
+        assert!(handle.join().unwrap());
+        assert!(memtable.creating.lock().unwrap().is_empty());
+        assert_eq!(
+            memtable.get(Key::from_slice(b"key1", 1)).unwrap().as_ref(),
+            b"value1"
+        );
+        assert!(memtable.get(Key::from_slice(b"key1", 2)).is_none());
+    }
+
+    #[test]
+    fn test_memtable_get_entry() {
+        let memtable = MemTable::new(0);
+        memtable
+            .put(Key::from_slice(b"key1", 0), b"value1")
+            .unwrap();
+        memtable.put(Key::from_slice(b"key2", 0), b"").unwrap();
+
+        assert_eq!(
+            memtable.get_entry(Key::from_slice(b"key1", 0)),
+            Lookup::Value(Bytes::from_static(b"value1"))
+        );
+        assert_eq!(
+            memtable.get_entry(Key::from_slice(b"key2", 0)),
+            Lookup::Tombstone
+        );
+        assert_eq!(
+            memtable.get_entry(Key::from_slice(b"key3", 0)),
+            Lookup::Missing
+        );
+
+        assert_eq!(
+            memtable.get(Key::from_slice(b"key1", 0)).unwrap().as_ref(),
+            b"value1"
+        );
+        assert!(memtable.get(Key::from_slice(b"key2", 0)).is_none());
+        assert!(memtable.get(Key::from_slice(b"key3", 0)).is_none());
+    }
+
+    #[test]
+    fn test_memtable_scan() {
+        let memtable = MemTable::new(0);
+        memtable
+            .put(Key::from_slice(b"key1", 1), b"value1")
+            .unwrap();
+        memtable
+            .put(Key::from_slice(b"key1", 2), b"value2")
+            .unwrap();
+        memtable
+            .put(Key::from_slice(b"key2", 1), b"value3")
+            .unwrap();
+        memtable
+            .put(Key::from_slice(b"key3", 1), b"value4")
+            .unwrap();
+
+        let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+        let mut entries = vec![];
+        while iter.is_valid() {
+            let key = iter.key();
+            entries.push((key.key_ref().to_vec(), key.version(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        assert_eq!(
+            entries,
+            vec![
+                (b"key1".to_vec(), 2, b"value2".to_vec()),
+                (b"key1".to_vec(), 1, b"value1".to_vec()),
+                (b"key2".to_vec(), 1, b"value3".to_vec()),
+                (b"key3".to_vec(), 1, b"value4".to_vec()),
+            ]
+        );
+
+        let mut iter = memtable.scan(
+            Bound::Excluded(Key::from_slice(b"key1", 1)),
+            Bound::Included(Key::from_slice(b"key2", 1)),
+        );
+        assert_eq!(iter.key().key_ref(), b"key2");
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+
+        drop(memtable);
+        let iter = MemTable::new(1).scan(Bound::Unbounded, Bound::Unbounded);
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn test_memtable_put_sync() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let memtable = MemTable::new_with_wal(0, &path).unwrap();
+        let sync = WriteOptions { sync: true };
+        memtable
+            .put_with_options(Key::from_slice(b"key1", 1), b"value1", sync)
+            .unwrap();
+        memtable
+            .put_batch_with_options(&[(Key::from_slice(b"key2", 1), b"value2")], sync)
+            .unwrap();
+        memtable
+            .put(Key::from_slice(b"key3", 1), b"value3")
+            .unwrap();
+        // Simulate a crash: the WAL's buffer is never flushed.
+        std::mem::forget(memtable);
+
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        let keys: Vec<_> = map.iter().map(|e| e.key().into_inner().to_vec()).collect();
+        assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+    }
+
+    #[test]
+    fn test_memtable_size_limits() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let memtable = MemTable::new_with_wal(0, &path)
+            .unwrap()
+            .with_size_limits(SizeLimits {
+                max_key_size: 4,
+                max_value_size: 6,
+            });
+        memtable
+            .put(Key::from_slice(b"key1", 1), b"value1")
+            .unwrap();
+        let err = memtable
+            .put(Key::from_slice(b"key10", 1), b"value1")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key of 5 bytes exceeds the limit of 4 bytes"
+        );
+        let err = memtable
+            .put_batch(&[
+                (Key::from_slice(b"key2", 1), b"value2"),
+                (Key::from_slice(b"key3", 1), b"value10"),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "value of 7 bytes exceeds the limit of 6 bytes"
+        );
+        assert_eq!(memtable.map.len(), 1);
+        drop(memtable);
+
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        assert_eq!(map.len(), 1);
+
+        // The defaults are the most the WAL can frame.
+        let memtable = MemTable::new(1);
+        let max = vec![b'x'; u16::MAX as usize];
+        memtable.put(Key::from_slice(&max, 1), &max).unwrap();
+        let too_long = vec![b'x'; u16::MAX as usize + 1];
+        assert!(memtable.put(Key::from_slice(&too_long, 1), b"").is_err());
+        assert!(memtable.put(Key::from_slice(b"k", 1), &too_long).is_err());
+    }
+
+    #[test]
+    fn test_memtable_options() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let options = MemTableOptions {
+            size_limits: SizeLimits {
+                max_key_size: 4,
+                max_value_size: 6,
+            },
+            wal: WalOptions {
+                max_batch_bytes: 50,
+            },
+        };
+
+        // Bad options are caught before the WAL is created or read.
+        for bad in [
+            MemTableOptions {
+                wal: WalOptions { max_batch_bytes: 0 },
+                ..options
+            },
+            MemTableOptions {
+                size_limits: SizeLimits {
+                    max_key_size: u16::MAX as usize + 1,
+                    max_value_size: 6,
+                },
+                ..options
+            },
+        ] {
+            assert!(MemTable::new_with_wal_options(0, &path, bad).is_err());
+            assert!(!path.exists());
+        }
+
+        let memtable = MemTable::new_with_wal_options(0, &path, options).unwrap();
+        memtable
+            .put(Key::from_slice(b"key1", 1), b"value1")
+            .unwrap();
+        assert!(memtable.put(Key::from_slice(b"key10", 1), b"v").is_err());
+        drop(memtable);
+
+        // The limits also hold for a memtable recovered with them.
+        let recovered = MemTable::recover_from_wal(&path, RecoverOptions::default()).unwrap();
+        recovered.put(Key::from_slice(b"key10", 1), b"v").unwrap();
+        drop(recovered);
+        let bad = MemTableOptions {
+            wal: WalOptions { max_batch_bytes: 0 },
+            ..options
+        };
+        assert!(
+            MemTable::recover_from_wal_with_options(&path, RecoverOptions::default(), bad).is_err()
+        );
+        let recovered =
+            MemTable::recover_from_wal_with_options(&path, RecoverOptions::default(), options)
+                .unwrap();
+        assert_eq!(recovered.map.len(), 2);
+        let err = recovered
+            .put(Key::from_slice(b"key11", 1), b"v")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key of 5 bytes exceeds the limit of 4 bytes"
+        );
+        recovered
+            .put(Key::from_slice(b"key2", 1), b"value2")
+            .unwrap();
+        drop(recovered);
+
+        let memtables = MemTable::recover_all_with_options(
+            vec![path.clone()],
+            RecoverOptions::default(),
+            options,
+        )
+        .unwrap();
+        assert_eq!(memtables[0].map.len(), 3);
+        assert!(memtables[0]
+            .put(Key::from_slice(b"k", 1), b"value10")
+            .is_err());
+    }
+
+    #[test]
+    fn test_memtable_backend_empty_range() {
+        for backend in [MemTableBackend::SkipList, MemTableBackend::BTree] {
+            let memtable = MemTable::new(0).with_backend(backend);
+            memtable
+                .put(Key::from_slice(b"key1", 1), b"value1")
+                .unwrap();
+            memtable
+                .put(Key::from_slice(b"key2", 1), b"value2")
+                .unwrap();
+            for (lower, upper) in [
+                (Bound::Included(b"key2"), Bound::Included(b"key1")),
+                (Bound::Excluded(b"key1"), Bound::Excluded(b"key1")),
+                (Bound::Included(b"key1"), Bound::Excluded(b"key1")),
+            ] {
+                let iter = memtable.scan(
+                    lower.map(|key| Key::from_slice(key, 1)),
+                    upper.map(|key| Key::from_slice(key, 1)),
+                );
+                assert!(!iter.is_valid(), "{:?}", backend);
+            }
+            let iter = memtable.scan(
+                Bound::Included(Key::from_slice(b"key1", 1)),
+                Bound::Included(Key::from_slice(b"key1", 1)),
+            );
+            assert_eq!(iter.value(), b"value1");
+        }
+    }
+
+    #[test]
+    fn test_memtable_index_hook() {
+        #[derive(Default)]
+        struct RecordingHook(Mutex<Vec<String>>);
+
+        impl IndexHook for RecordingHook {
+            fn on_put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
+                if value == b"bad" {
+                    bail!("can't index {:?}", String::from_utf8_lossy(key.key_ref()));
+                }
+                self.0.lock().unwrap().push(format!(
+                    "put {}@{}={}",
+                    String::from_utf8_lossy(key.key_ref()),
+                    key.version(),
+                    String::from_utf8_lossy(value)
+                ));
+                Ok(())
+            }
+
+            fn on_delete(&self, key: KeySlice) -> Result<()> {
+                self.0.lock().unwrap().push(format!(
+                    "delete {}@{}",
+                    String::from_utf8_lossy(key.key_ref()),
+                    key.version()
+                ));
+                Ok(())
+            }
+        }
+
+        let hook = Arc::new(RecordingHook::default());
+        let memtable = MemTable::new(0).with_index_hook(hook.clone());
+        memtable
+            .put_batch(&[
+                (Key::from_slice(b"key1", 1), b"value1"),
+                (Key::from_slice(b"key2", 1), b"value2"),
+            ])
+            .unwrap();
+        memtable.put(Key::from_slice(b"key1", 2), b"").unwrap();
+        let err = memtable
+            .put(Key::from_slice(b"key3", 1), b"bad")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "can't index \"key3\"");
+        assert_eq!(
+            memtable.get_entry(Key::from_slice(b"key3", 1)),
+            Lookup::Missing
+        );
+        assert!(memtable
+            .put_if_absent(Key::from_slice(b"key3", 1), b"value3")
+            .unwrap());
+
+        assert_eq!(
+            *hook.0.lock().unwrap(),
+            [
+                "put key1@1=value1",
+                "put key2@1=value2",
+                "delete key1@2",
+                "put key3@1=value3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memtable_recover_all() {
+        let dir = tempdir().unwrap();
+        // Named so that the file order differs from the id order.
+        let mut paths = vec![];
+        for (name, id) in [("a.wal", 3), ("b.wal", 1), ("c.wal", 2)] {
+            let path = dir.path().join(name);
+            let memtable = MemTable::new_with_wal(id, &path).unwrap();
+            let value = format!("value{}", id);
+            memtable
+                .put_with_options(
+                    Key::from_slice(b"key", id as u64),
+                    value.as_bytes(),
+                    WriteOptions { sync: true },
+                )
+                .unwrap();
+            paths.push(path);
+        }
+
+        let memtables = MemTable::recover_all(paths, RecoverOptions::default()).unwrap();
+        let ids: Vec<_> = memtables.iter().map(|memtable| memtable.id()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        for memtable in &memtables {
+            let version = memtable.id() as u64;
+            let value = memtable.get(Key::from_slice(b"key", version)).unwrap();
+            assert_eq!(value.as_ref(), format!("value{}", version).as_bytes());
+            assert_eq!(memtable.approximate_size(), 3 + 8 + 6);
+        }
+
+        // The recovered memtables keep appending to their WALs.
+        let memtable = &memtables[0];
+        memtable
+            .put(Key::from_slice(b"key", 10), b"value10")
+            .unwrap();
+        memtable.wal.as_ref().unwrap().sync().unwrap();
+        let memtable =
+            MemTable::recover_from_wal(dir.path().join("b.wal"), RecoverOptions::default())
+                .unwrap();
+        assert_eq!(memtable.id(), 1);
+        assert!(memtable.get(Key::from_slice(b"key", 10)).is_some());
+
+        // A WAL torn while it was being created is skipped in lenient mode.
+        let torn = dir.path().join("d.wal");
+        std::fs::write(&torn, b"LSM").unwrap();
+        let paths = vec![dir.path().join("a.wal"), torn.clone()];
+        assert!(MemTable::recover_all(paths.clone(), RecoverOptions::default()).is_err());
+        let options = RecoverOptions {
+            checksum_mode: ChecksumMode::Lenient,
+        };
+        let memtables = MemTable::recover_all(paths, options).unwrap();
+        assert_eq!(memtables.len(), 1);
+        assert_eq!(memtables[0].id(), 3);
+    }
 }
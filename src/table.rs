@@ -1,10 +1,25 @@
-use std::{fs::File, os::unix::fs::FileExt, path::Path};
+#[cfg(test)]
+use std::cell::Cell;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crossbeam::queue::ArrayQueue;
 
 use crate::{block::BlockMeta, key::KeyBytes};
 
 use anyhow::Result;
 
 /// An SSTable.
+// Nothing reads SSTables yet, so none of the fields are read either.
+#[allow(dead_code)]
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
     pub(crate) file: FileObject,
@@ -20,36 +35,488 @@ pub struct SsTable {
     max_ts: u64,
 }
 
+/// How a newly written file is flushed to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// `fsync`: flush the data and all of the file's metadata.
+    #[default]
+    All,
+    /// `fdatasync`: flush the data and only the metadata needed to read it back
+    /// (such as the size), skipping things like the modification time. Cheaper,
+    /// and the contents are just as durable, but the skipped metadata may be
+    /// stale after a crash.
+    Data,
+}
+
+#[cfg(test)]
+thread_local! {
+    static FILE_OPENS: Cell<usize> = const { Cell::new(0) };
+}
+
+// All `FileObject` opens go through here, so tests can count them.
+fn open_file(options: &OpenOptions, path: &Path) -> std::io::Result<File> {
+    #[cfg(test)]
+    FILE_OPENS.with(|opens| opens.set(opens.get() + 1));
+    options.open(path)
+}
+
 /// A file object.
-pub struct FileObject(Option<File>, u64);
+pub struct FileObject {
+    file: Option<File>,
+    size: AtomicU64,
+    /// Whether the file was opened with `O_DIRECT`, so reads must be aligned.
+    direct: bool,
+    /// Serializes appends, so that the size only grows once a write is in.
+    append_lock: Mutex<()>,
+}
 
 impl FileObject {
+    fn from_file(file: File, size: u64, direct: bool) -> Self {
+        Self {
+            file: Some(file),
+            size: AtomicU64::new(size),
+            direct,
+            append_lock: Mutex::new(()),
+        }
+    }
+
     pub fn new(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
-        Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
+        Self::new_with_sync(path, data, SyncMode::All)
+    }
+
+    /// Create a file object, flushing it to disk according to `sync`.
+    ///
+    /// The data is written and synced through one handle, and the file is then
+    /// reopened read-only.
+    pub fn new_with_sync(path: &Path, data: Vec<u8>, sync: SyncMode) -> Result<Self> {
+        let mut file = open_file(
+            File::options().write(true).create(true).truncate(true),
+            path,
+        )?;
+        file.write_all(&data)?;
+        match sync {
+            SyncMode::All => file.sync_all()?,
+            SyncMode::Data => file.sync_data()?,
+        }
+        drop(file);
+        Ok(Self::from_file(
+            open_file(File::options().read(true), path)?,
             data.len() as u64,
+            false,
         ))
     }
 
     pub fn open(path: &Path) -> Result<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
+        let file = open_file(File::options().read(true), path)?;
         let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
+        Ok(Self::from_file(file, size, false))
     }
 
-    pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        let mut data = vec![0; len as usize];
-        self.0
-            .as_ref()
-            .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
+    /// Open a file for reading with `O_DIRECT`, bypassing the page cache, for
+    /// large one-off reads such as compaction inputs that would otherwise
+    /// evict hotter data. Reads are widened to aligned offsets and lengths, so
+    /// small reads cost a whole aligned block each.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub fn open_direct(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
 
+        let file = open_file(
+            File::options().read(true).custom_flags(libc::O_DIRECT),
+            path,
+        )?;
+        let size = file.metadata()?.len();
+        Ok(Self::from_file(file, size, true))
+    }
+
+    /// Create an empty file that can be grown with [`FileObject::append`], for
+    /// append-only files such as a manifest.
+    pub fn create_appendable(path: &Path) -> Result<Self> {
+        let file = open_file(
+            File::options().read(true).write(true).create_new(true),
+            path,
+        )?;
+        Ok(Self::from_file(file, 0, false))
+    }
+
+    /// Open an existing file to grow it with [`FileObject::append`], such as a
+    /// manifest left by an earlier run. Appends go after what's already there.
+    pub fn open_appendable(path: &Path) -> Result<Self> {
+        let file = open_file(File::options().read(true).write(true), path)?;
+        let size = file.metadata()?.len();
+        Ok(Self::from_file(file, size, false))
+    }
+
+    /// Append `data` to the end of the file, returning the offset it was
+    /// written at. Only works on files from [`FileObject::create_appendable`]
+    /// or [`FileObject::open_appendable`].
+    ///
+    /// A failed append leaves the size as it was, and the next append writes
+    /// over whatever part of `data` made it to the file.
+    pub fn append(&self, data: &[u8]) -> Result<u64> {
+        let _guard = self.append_lock.lock().unwrap();
+        let offset = self.size.load(Ordering::SeqCst);
+        self.file().write_all_at(data, offset)?;
+        self.size
+            .store(offset + data.len() as u64, Ordering::SeqCst);
+        Ok(offset)
+    }
+
+    pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        self.read_into(offset, len, &mut data)?;
         Ok(data)
     }
 
+    /// Like [`FileObject::read`], but into `buf`, replacing its contents and
+    /// reusing its allocation where it's large enough.
+    pub fn read_into(&self, offset: u64, len: u64, buf: &mut Vec<u8>) -> Result<()> {
+        if self.direct {
+            return os::read_direct_into(self.file(), offset, len, buf);
+        }
+        buf.clear();
+        buf.resize(len as usize, 0);
+        self.file().read_exact_at(&mut buf[..], offset)?;
+
+        Ok(())
+    }
+
     pub fn size(&self) -> u64 {
-        self.1
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Flush the file's data with `fdatasync`, see [`SyncMode::Data`].
+    pub fn sync_data(&self) -> Result<()> {
+        self.file().sync_data()?;
+        Ok(())
+    }
+
+    /// Hint that the file will be read front to back, so the OS can read ahead
+    /// more aggressively. A no-op where `posix_fadvise` isn't available.
+    pub fn advise_sequential(&self) -> Result<()> {
+        os::advise(self.file(), Advice::Sequential)
+    }
+
+    /// Hint that the file will be read at scattered offsets, so the OS can skip
+    /// read-ahead. A no-op where `posix_fadvise` isn't available.
+    pub fn advise_random(&self) -> Result<()> {
+        os::advise(self.file(), Advice::Random)
+    }
+
+    fn file(&self) -> &File {
+        self.file.as_ref().unwrap()
+    }
+}
+
+/// The default for [`ReadBufferPool::with_max_buffer_capacity`].
+pub const DEFAULT_MAX_POOLED_BUFFER: usize = 1 << 20;
+
+/// A bounded pool of read buffers, so that repeated block reads with
+/// [`FileObject::read_into`] can reuse allocations instead of making new ones.
+pub struct ReadBufferPool {
+    buffers: ArrayQueue<Vec<u8>>,
+    max_buffer_capacity: usize,
+}
+
+impl ReadBufferPool {
+    /// Create a pool holding up to `capacity` idle buffers. Panics if
+    /// `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: ArrayQueue::new(capacity),
+            max_buffer_capacity: DEFAULT_MAX_POOLED_BUFFER,
+        }
+    }
+
+    /// Set the largest allocation, in bytes, that an idle buffer may keep, so
+    /// that one oversized read doesn't pin its memory in the pool for good.
+    pub fn with_max_buffer_capacity(mut self, max_buffer_capacity: usize) -> Self {
+        self.max_buffer_capacity = max_buffer_capacity;
+        self
+    }
+
+    /// Take an idle buffer, or a new empty one if there are none.
+    pub fn get(&self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    /// Give a buffer back for reuse. It is dropped if the pool is full, or if
+    /// its allocation is larger than the pool keeps.
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > self.max_buffer_capacity {
+            return;
+        }
+        buf.clear();
+        let _ = self.buffers.push(buf);
+    }
+
+    /// Get the number of idle buffers.
+    pub fn idle(&self) -> usize {
+        self.buffers.len()
+    }
+}
+
+/// An access pattern hint for [`os::advise`].
+enum Advice {
+    Sequential,
+    Random,
+}
+
+/// `O_DIRECT` reads and `posix_fadvise`, where the OS has them.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+mod os {
+    use std::{fs::File, os::fd::AsRawFd, os::unix::fs::FileExt};
+
+    use anyhow::Result;
+
+    use super::Advice;
+
+    /// The offset, length and memory alignment that `O_DIRECT` reads must
+    /// have. 4096 covers the logical block size of common devices.
+    const DIRECT_IO_ALIGN: usize = 4096;
+
+    pub(super) fn advise(file: &File, advice: Advice) -> Result<()> {
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => libc::POSIX_FADV_RANDOM,
+        };
+        // SAFETY: `file` is open for as long as the borrow lasts.
+        let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret).into());
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes at `offset` from a file opened with `O_DIRECT` into
+    /// `buf`, replacing its contents. The aligned read lands at an aligned
+    /// spot inside `buf`'s own allocation, and the requested bytes are then
+    /// moved to the front, so a reused `buf` needs no new allocation.
+    pub(super) fn read_direct_into(
+        file: &File,
+        offset: u64,
+        len: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        buf.clear();
+        if len == 0 {
+            return Ok(());
+        }
+        let align = DIRECT_IO_ALIGN as u64;
+        let start = offset / align * align;
+        let end = (offset + len).div_ceil(align) * align;
+        let aligned_len = (end - start) as usize;
+        // Leave room to start the aligned range wherever the allocation
+        // first hits an aligned address.
+        buf.resize(aligned_len + DIRECT_IO_ALIGN - 1, 0);
+        let addr = buf.as_ptr() as usize;
+        let pad = addr.next_multiple_of(DIRECT_IO_ALIGN) - addr;
+        let aligned = &mut buf[pad..pad + aligned_len];
+
+        // The aligned range may run past the end of the file, so read until
+        // the requested bytes are in rather than filling the whole range.
+        let want = (offset + len - start) as usize;
+        let mut filled = 0;
+        while filled < want {
+            // Short reads end on an aligned boundary unless they hit the end
+            // of the file, so every read starts aligned.
+            let n = file.read_at(&mut aligned[filled..], start + filled as u64)?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            filled += n;
+        }
+        let from = pad + (offset - start) as usize;
+        buf.copy_within(from..from + len as usize, 0);
+        buf.truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// Elsewhere, hints are no-ops and no file is ever opened with `O_DIRECT`.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+mod os {
+    use std::fs::File;
+
+    use anyhow::Result;
+
+    use super::Advice;
+
+    pub(super) fn advise(_file: &File, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn read_direct_into(
+        _file: &File,
+        _offset: u64,
+        _len: u64,
+        _buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        unreachable!("`O_DIRECT` is not supported on this platform")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::block::Block;
+
+    use super::*;
+
+    #[test]
+    fn test_file_object_sync_data() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let block = Block {
+            data: b"key1value1key2value2".to_vec(),
+            offsets: vec![0, 10],
+        };
+        let data = block.encode().as_ref().to_vec();
+
+        let file = FileObject::new_with_sync(&path, data.clone(), SyncMode::Data).unwrap();
+        file.sync_data().unwrap();
+        assert_eq!(file.size(), data.len() as u64);
+        assert_eq!(file.read(0, file.size()).unwrap(), data);
+
+        let file = FileObject::open(&path).unwrap();
+        assert_eq!(file.read(0, file.size()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_file_object_advise() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data: Vec<u8> = (0..=255).cycle().take(64 * 1024).collect();
+        FileObject::new(&path, data.clone()).unwrap();
+
+        let file = FileObject::open(&path).unwrap();
+        file.advise_sequential().unwrap();
+        let mut read = vec![];
+        for offset in (0..file.size()).step_by(4096) {
+            read.extend(file.read(offset, 4096).unwrap());
+        }
+        assert_eq!(read, data);
+
+        file.advise_random().unwrap();
+        assert_eq!(file.read(1000, 10).unwrap(), data[1000..1010]);
+    }
+
+    #[test]
+    fn test_file_object_append() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("MANIFEST");
+        let file = FileObject::create_appendable(&path).unwrap();
+        assert_eq!(file.size(), 0);
+
+        let first = file.append(b"hello").unwrap();
+        let second = file.append(b"world!").unwrap();
+        assert_eq!((first, second), (0, 5));
+        assert_eq!(file.size(), 11);
+        assert_eq!(file.read(first, 5).unwrap(), b"hello");
+        assert_eq!(file.read(second, 6).unwrap(), b"world!");
+        file.sync_data().unwrap();
+
+        let file = FileObject::open(&path).unwrap();
+        assert_eq!(file.read(0, file.size()).unwrap(), b"helloworld!");
+        assert!(file.append(b"read-only").is_err());
+        assert_eq!(file.size(), 11);
+
+        // Reopened for appending, it carries on from the end.
+        assert!(FileObject::create_appendable(&path).is_err());
+        let file = FileObject::open_appendable(&path).unwrap();
+        assert_eq!(file.size(), 11);
+        assert_eq!(file.append(b"again").unwrap(), 11);
+        file.sync_data().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld!again");
+        assert!(FileObject::open_appendable(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_file_object_new_opens() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data = b"some sstable data".to_vec();
+
+        let opens = FILE_OPENS.with(|opens| opens.get());
+        let file = FileObject::new(&path, data.clone()).unwrap();
+        assert_eq!(FILE_OPENS.with(|opens| opens.get()) - opens, 2);
+
+        assert_eq!(file.read(0, file.size()).unwrap(), data);
+        assert!(file.append(b"read-only").is_err());
+        assert_eq!(file.size(), data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn test_file_object_open_direct() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data: Vec<u8> = (0..=255).cycle().take(3 * 4096 + 100).collect();
+        FileObject::new(&path, data.clone()).unwrap();
+
+        let buffered = FileObject::open(&path).unwrap();
+        let direct = FileObject::open_direct(&path).unwrap();
+        assert_eq!(direct.size(), data.len() as u64);
+        for (offset, len) in [(0, 4096), (1000, 10), (4000, 5000), (8192, 4196), (0, 0)] {
+            assert_eq!(
+                direct.read(offset, len).unwrap(),
+                buffered.read(offset, len).unwrap()
+            );
+        }
+        assert_eq!(direct.read(0, direct.size()).unwrap(), data);
+        assert!(direct.read(12000, 1000).is_err());
+
+        // Reads into a reused buffer land in its allocation, whatever its
+        // alignment, and replace what it held.
+        let mut buf = Vec::with_capacity(4 * 4096);
+        let ptr = buf.as_ptr();
+        for (offset, len) in [(1000, 10), (4000, 5000), (0, 4096), (100, 0)] {
+            buf.extend_from_slice(b"stale");
+            direct.read_into(offset, len, &mut buf).unwrap();
+            let (offset, len) = (offset as usize, len as usize);
+            assert_eq!(buf, data[offset..offset + len]);
+            assert_eq!(buf.as_ptr(), ptr);
+        }
+    }
+
+    #[test]
+    fn test_read_buffer_pool() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data: Vec<u8> = (0..=255).cycle().take(4 * 1024).collect();
+        let file = FileObject::new(&path, data.clone()).unwrap();
+
+        let pool = ReadBufferPool::new(2);
+        let mut buf = pool.get();
+        file.read_into(0, 1024, &mut buf).unwrap();
+        assert_eq!(buf, data[..1024]);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.idle(), 1);
+
+        // The next read of the same size reuses the allocation.
+        let mut buf = pool.get();
+        assert!(buf.is_empty());
+        file.read_into(1024, 1024, &mut buf).unwrap();
+        assert_eq!(buf, data[1024..2048]);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.idle(), 0);
+
+        // Beyond its capacity, the pool drops buffers instead of keeping them.
+        pool.put(buf);
+        pool.put(vec![]);
+        pool.put(vec![]);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn test_read_buffer_pool_max_buffer_capacity() {
+        let pool = ReadBufferPool::new(4).with_max_buffer_capacity(1024);
+        pool.put(Vec::with_capacity(1024));
+        assert_eq!(pool.idle(), 1);
+        pool.put(Vec::with_capacity(1025));
+        assert_eq!(pool.idle(), 1);
+        assert!(pool.get().capacity() <= 1024);
     }
 }
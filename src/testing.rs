@@ -0,0 +1,337 @@
+//! A seeded check of any ordered source of versioned entries against a
+//! `BTreeMap` oracle, shared by the tests of every such source.
+
+use std::{cmp::Reverse, collections::BTreeMap, ops::Bound};
+
+use crate::{
+    byte::Bytes,
+    iterators::StorageIterator,
+    key::{Key, KeySlice},
+    mem_table::{Lookup, MemTable, MemTableIterator},
+};
+
+/// A xorshift generator, so a failing case reproduces from its seed.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Something that can be scanned over a range of versioned keys, expected to
+/// yield them in `Key` order: by key, then newest version first.
+pub(crate) trait ScanSource {
+    type Iter: StorageIterator;
+
+    fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> Self::Iter;
+
+    /// Look up `key` at exactly its version, like [`MemTable::get`].
+    fn get(&self, key: KeySlice) -> Option<Bytes>;
+
+    /// Like [`ScanSource::get`], telling a tombstone apart from a missing key.
+    fn get_entry(&self, key: KeySlice) -> Lookup;
+}
+
+impl ScanSource for MemTable {
+    type Iter = MemTableIterator;
+
+    fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        MemTable::scan(self, lower, upper)
+    }
+
+    fn get(&self, key: KeySlice) -> Option<Bytes> {
+        MemTable::get(self, key)
+    }
+
+    fn get_entry(&self, key: KeySlice) -> Lookup {
+        MemTable::get_entry(self, key)
+    }
+}
+
+/// The newest version of every live key at `read_ts`, collapsed from a
+/// full scan.
+pub(crate) fn scan_at(source: &impl ScanSource, read_ts: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut iter = source.scan(Bound::Unbounded, Bound::Unbounded);
+    let mut res = vec![];
+    let mut prev_key: Option<Vec<u8>> = None;
+    while iter.is_valid() {
+        let key = iter.key();
+        if key.version() <= read_ts && prev_key.as_deref() != Some(key.key_ref()) {
+            prev_key = Some(key.key_ref().to_vec());
+            if !iter.value().is_empty() {
+                res.push((key.key_ref().to_vec(), iter.value().to_vec()));
+            }
+        }
+        iter.next().unwrap();
+    }
+    res
+}
+
+/// The newest version of `key` at `read_ts`, found by seeking.
+pub(crate) fn get_at(source: &impl ScanSource, key: &[u8], read_ts: u64) -> Option<Vec<u8>> {
+    let iter = source.scan(
+        Bound::Included(Key::from_slice(key, read_ts)),
+        Bound::Included(Key::from_slice(key, 0)),
+    );
+    if iter.is_valid() && !iter.value().is_empty() {
+        Some(iter.value().to_vec())
+    } else {
+        None
+    }
+}
+
+/// Random puts and deletes, and what reading them back must return.
+pub(crate) struct Oracle {
+    keys: u64,
+    writes: u64,
+    entries: BTreeMap<(Vec<u8>, Reverse<u64>), Vec<u8>>,
+}
+
+impl Oracle {
+    /// Write to `keys` random keys at versions 1 to `writes`, deleting about
+    /// one in four times.
+    pub(crate) fn random(rng: &mut Rng, keys: u64, writes: u64) -> Self {
+        let mut entries = BTreeMap::new();
+        for ts in 1..=writes {
+            let key = Self::key(rng.next(keys));
+            let value = match rng.next(4) {
+                0 => vec![],
+                _ => format!("value{}", ts).into_bytes(),
+            };
+            entries.insert((key, Reverse(ts)), value);
+        }
+        Self {
+            keys,
+            writes,
+            entries,
+        }
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("key{:02}", i).into_bytes()
+    }
+
+    /// Every write, with an empty value for a delete, to apply to a source.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (KeySlice<'_>, &[u8])> {
+        self.entries
+            .iter()
+            .map(|((key, Reverse(ts)), value)| (Key::from_slice(key, *ts), &value[..]))
+    }
+
+    /// Check that a full scan of `source` yields exactly the written entries
+    /// in order, that `get` and `get_entry` find every write at its version,
+    /// and that `get_at` and `scan_at` agree with the oracle at a few random
+    /// read timestamps. Returns what went wrong, if anything.
+    pub(crate) fn check(&self, source: &impl ScanSource, rng: &mut Rng) -> Result<(), String> {
+        let mut iter = source.scan(Bound::Unbounded, Bound::Unbounded);
+        for ((key, Reverse(ts)), value) in &self.entries {
+            if !iter.is_valid() {
+                return Err(format!("scan ended before {:?}@{}", key, ts));
+            }
+            let actual = (iter.key().key_ref(), iter.key().version(), iter.value());
+            if actual != (&key[..], *ts, &value[..]) {
+                return Err(format!(
+                    "scan yielded {:?} where {:?} was expected",
+                    actual,
+                    (key, ts, value)
+                ));
+            }
+            iter.next().unwrap();
+        }
+        if iter.is_valid() {
+            return Err("scan yielded more entries than were written".to_string());
+        }
+
+        for ((key, Reverse(ts)), value) in &self.entries {
+            let key = Key::from_slice(key, *ts);
+            let (expected, expected_entry) = match &value[..] {
+                [] => (None, Lookup::Tombstone),
+                value => (
+                    Some(Bytes::copy_from_slice(value)),
+                    Lookup::Value(Bytes::copy_from_slice(value)),
+                ),
+            };
+            let actual = source.get(key);
+            if actual != expected {
+                return Err(format!(
+                    "get of {:?} returned {:?} instead of {:?}",
+                    key, actual, expected
+                ));
+            }
+            let actual = source.get_entry(key);
+            if actual != expected_entry {
+                return Err(format!(
+                    "get_entry of {:?} returned {:?} instead of {:?}",
+                    key, actual, expected_entry
+                ));
+            }
+        }
+        // Versions start at 1, so nothing was written at version 0.
+        for key in (0..self.keys).map(Self::key) {
+            let key = Key::from_slice(&key, 0);
+            if source.get_entry(key) != Lookup::Missing || source.get(key).is_some() {
+                return Err(format!("{:?} was found though it was never written", key));
+            }
+        }
+
+        for _ in 0..10 {
+            let read_ts = rng.next(self.writes + 1);
+            let mut expected = vec![];
+            for key in (0..self.keys).map(Self::key) {
+                let visible = self
+                    .entries
+                    .range((key.clone(), Reverse(read_ts))..)
+                    .next()
+                    .filter(|((k, _), value)| *k == key && !value.is_empty())
+                    .map(|(_, value)| value.clone());
+                let actual = get_at(source, &key, read_ts);
+                if actual != visible {
+                    return Err(format!(
+                        "get of {:?} at {} returned {:?} instead of {:?}",
+                        key, read_ts, actual, visible
+                    ));
+                }
+                if let Some(value) = visible {
+                    expected.push((key, value));
+                }
+            }
+            if scan_at(source, read_ts) != expected {
+                return Err(format!("scan at {} doesn't match", read_ts));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entries kept in whatever order they were given, and yielded in that
+    /// order, so it's only a valid source if they were sorted right.
+    struct VecSource(Vec<(Vec<u8>, u64, Vec<u8>)>);
+
+    struct VecSourceIterator {
+        entries: Vec<(Vec<u8>, u64, Vec<u8>)>,
+        idx: usize,
+    }
+
+    impl StorageIterator for VecSourceIterator {
+        fn key(&self) -> KeySlice<'_> {
+            let (key, ts, _) = &self.entries[self.idx];
+            Key::from_slice(key, *ts)
+        }
+
+        fn value(&self) -> &[u8] {
+            &self.entries[self.idx].2
+        }
+
+        fn is_valid(&self) -> bool {
+            self.idx < self.entries.len()
+        }
+
+        fn next(&mut self) -> anyhow::Result<()> {
+            self.idx += 1;
+            Ok(())
+        }
+    }
+
+    impl ScanSource for VecSource {
+        type Iter = VecSourceIterator;
+
+        fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> VecSourceIterator {
+            let entries = self
+                .0
+                .iter()
+                .filter(|(key, ts, _)| {
+                    let key = Key::from_slice(key, *ts);
+                    let above = match lower {
+                        Bound::Included(lower) => key >= lower,
+                        Bound::Excluded(lower) => key > lower,
+                        Bound::Unbounded => true,
+                    };
+                    let below = match upper {
+                        Bound::Included(upper) => key <= upper,
+                        Bound::Excluded(upper) => key < upper,
+                        Bound::Unbounded => true,
+                    };
+                    above && below
+                })
+                .cloned()
+                .collect();
+            VecSourceIterator { entries, idx: 0 }
+        }
+
+        fn get(&self, key: KeySlice) -> Option<Bytes> {
+            match self.get_entry(key) {
+                Lookup::Value(value) => Some(value),
+                Lookup::Missing | Lookup::Tombstone => None,
+            }
+        }
+
+        fn get_entry(&self, key: KeySlice) -> Lookup {
+            let found = self
+                .0
+                .iter()
+                .find(|(k, ts, _)| (&k[..], *ts) == (key.key_ref(), key.version()));
+            match found {
+                None => Lookup::Missing,
+                Some((_, _, value)) if value.is_empty() => Lookup::Tombstone,
+                Some((_, _, value)) => Lookup::Value(Bytes::copy_from_slice(value)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_oracle_rejects_misordered_source() {
+        let mut rng = Rng(7);
+        let oracle = Oracle::random(&mut rng, 20, 200);
+        let mut entries: Vec<_> = oracle
+            .entries()
+            .map(|(key, value)| (key.key_ref().to_vec(), key.version(), value.to_vec()))
+            .collect();
+
+        // A classic slip: sorting versions oldest first.
+        entries.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        let err = oracle
+            .check(&VecSource(entries.clone()), &mut rng)
+            .unwrap_err();
+        assert!(err.starts_with("scan yielded"), "{}", err);
+
+        // Sorted in `Key` order, the same entries pass.
+        entries.sort_by(|a, b| Key::from_slice(&a.0, a.1).cmp(&Key::from_slice(&b.0, b.1)));
+        oracle.check(&VecSource(entries.clone()), &mut rng).unwrap();
+
+        // Another: a lookup that takes a tombstone for a missing key, which
+        // would send readers on to older sources.
+        let err = oracle
+            .check(&TombstonesMissing(VecSource(entries)), &mut rng)
+            .unwrap_err();
+        assert!(err.starts_with("get_entry of"), "{}", err);
+    }
+
+    struct TombstonesMissing(VecSource);
+
+    impl ScanSource for TombstonesMissing {
+        type Iter = VecSourceIterator;
+
+        fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> VecSourceIterator {
+            self.0.scan(lower, upper)
+        }
+
+        fn get(&self, key: KeySlice) -> Option<Bytes> {
+            self.0.get(key)
+        }
+
+        fn get_entry(&self, key: KeySlice) -> Lookup {
+            match self.0.get_entry(key) {
+                Lookup::Tombstone => Lookup::Missing,
+                lookup => lookup,
+            }
+        }
+    }
+}
@@ -1,111 +1,848 @@
 use std::{
     fs::{File, OpenOptions},
-    io::{BufWriter, Write},
+    io::{BufWriter, Read, Write},
     path::Path,
     sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use crossbeam_skiplist::SkipMap;
 
-use crate::byte::ByteUtil;
+use crate::{
+    byte::{ByteReader, ByteUtil, Bytes},
+    key::{KeyBytes, KeySlice},
+};
+
+/// The format version written in the header of every WAL file.
+pub const WAL_FORMAT_VERSION: u8 = 1;
+
+/// The start of every WAL file.
+const WAL_MAGIC: &[u8; 4] = b"LSMW";
+
+// Magic, version (u8), memtable id (u64), created at (u64), and a crc32 of
+// all of those (u32).
+const HEADER_SIZE: usize = WAL_MAGIC.len() + 1 + 8 + 8 + 4;
+
+/// The most entry bytes a single frame can hold, since the frame's length,
+/// which also covers its flags byte, is a u32.
+pub const MAX_FRAME_BYTES: usize = u32::MAX as usize - 1;
+
+// Frame flags.
+/// The frame continues the batch of the frame before it.
+const FRAME_CONTINUES: u8 = 1;
+/// The batch continues in the next frame.
+const FRAME_MORE: u8 = 2;
+
+enum Frame<T> {
+    Decoded(T),
+    Corrupt,
+    Truncated,
+}
 
 pub struct Wal {
     file: Arc<Mutex<BufWriter<File>>>,
+    max_batch_bytes: usize,
+    header: Option<WalHeader>,
+}
+
+/// Identifies the memtable a WAL file belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalHeader {
+    pub memtable_id: usize,
+    /// When the file was created, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// How a WAL frames batches, see [`Wal::new_with_options`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalOptions {
+    /// Split batches whose entries take more than this many bytes across
+    /// several frames, each with its own checksum. Recovery still replays such
+    /// a batch all or nothing. An entry is never split, so one that is larger
+    /// than the limit gets a frame to itself. Must be from 1 to
+    /// [`MAX_FRAME_BYTES`].
+    pub max_batch_bytes: usize,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            max_batch_bytes: MAX_FRAME_BYTES,
+        }
+    }
+}
+
+impl WalOptions {
+    /// Check that the options are in range.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_batch_bytes == 0 || self.max_batch_bytes > MAX_FRAME_BYTES {
+            bail!("max batch bytes {} out of range", self.max_batch_bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Options for [`Wal::recover`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecoverOptions {
+    /// What to do with a batch whose checksum doesn't match.
+    pub checksum_mode: ChecksumMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumMode {
+    /// Abort recovery with an error.
+    #[default]
+    Strict,
+    /// Log a warning, skip the batch and keep replaying the rest of the log.
+    ///
+    /// The skipped batch is silently lost, so this is only meant for
+    /// best-effort recovery of a damaged log.
+    Lenient,
 }
 
 impl Wal {
-    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+    /// Create a WAL for the memtable with id `memtable_id`. The header is
+    /// synced before this returns, so a crash can only tear it while the file
+    /// is still being created, before it holds any writes.
+    pub fn new(path: impl AsRef<Path>, memtable_id: usize) -> Result<Self> {
+        Self::new_with_options(path, memtable_id, WalOptions::default())
+    }
+
+    /// Like [`Wal::new`], framing batches according to `options`, which are
+    /// checked before the file is created.
+    pub fn new_with_options(
+        path: impl AsRef<Path>,
+        memtable_id: usize,
+        options: WalOptions,
+    ) -> Result<Self> {
+        options.validate()?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .context("failed to create WAL")?;
+        let header = WalHeader {
+            memtable_id,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        };
+        let mut buf = WAL_MAGIC.to_vec();
+        buf.push(WAL_FORMAT_VERSION);
+        buf.put_u64(header.memtable_id as u64);
+        buf.put_u64(header.created_at);
+        buf.put_u32(crc32fast::hash(&buf));
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            max_batch_bytes: options.max_batch_bytes,
+            header: Some(header),
+        })
+    }
+
+    /// Get the header, or `None` for a WAL recovered leniently from a file
+    /// whose header was torn, see [`Wal::recover`].
+    pub fn header(&self) -> Option<WalHeader> {
+        self.header
+    }
+
+    /// Read just the header of the WAL at `path`, see [`Wal::header`]. Returns
+    /// `None` if the file is empty or its header was torn by a crash.
+    pub fn read_header(path: impl AsRef<Path>) -> Result<Option<WalHeader>> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        File::open(path)
+            .context("failed to read WAL header")?
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut buf)?;
+        if Self::is_torn_header(&buf) {
+            return Ok(None);
+        }
+        Ok(Some(Self::split_header(&buf)?.0))
+    }
+
+    // Whether `buf` is all that made it to disk of a header whose write was
+    // cut short: nothing at all, or the start of the magic and maybe more.
+    fn is_torn_header(buf: &[u8]) -> bool {
+        let magic_len = buf.len().min(WAL_MAGIC.len());
+        buf.len() < HEADER_SIZE && buf[..magic_len] == WAL_MAGIC[..magic_len]
+    }
+
+    // Split the header off the start of a WAL file, returning it and the
+    // frames after it.
+    fn split_header(buf: &[u8]) -> Result<(WalHeader, &[u8])> {
+        if buf.is_empty() {
+            bail!("missing WAL header");
+        }
+        if !buf.starts_with(WAL_MAGIC) {
+            bail!("unrecognized WAL header");
+        }
+        let Some((header, rest)) = buf.split_at_checked(HEADER_SIZE) else {
+            bail!("truncated WAL header");
+        };
+        let mut header = &header[WAL_MAGIC.len()..];
+        let checksum = crc32fast::hash(&buf[..HEADER_SIZE - 4]);
+        let version = header[0];
+        header.advance(1);
+        let memtable_id = header.read_u64().unwrap() as usize;
+        let created_at = header.read_u64().unwrap();
+        if header.read_u32() != Some(checksum) {
+            bail!("corrupt WAL header");
+        }
+        if version != WAL_FORMAT_VERSION {
+            bail!("unsupported WAL format version {}", version);
+        }
+        let header = WalHeader {
+            memtable_id,
+            created_at,
+        };
+        Ok((header, rest))
+    }
+
+    /// Replay the WAL at `path` into `skiplist`, and reopen it for appending.
+    ///
+    /// In lenient mode, whatever follows the last complete batch, such as a
+    /// torn tail, a corrupt frame, or the first frames of a batch that never
+    /// got its last one, is cut off the file first, so that new frames don't
+    /// land after it. A file that is empty or has a torn header, which a crash
+    /// while creating it can leave behind, is taken for an empty log, but the
+    /// returned WAL has no header and can't be appended to.
+    pub fn recover(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        options: RecoverOptions,
+    ) -> Result<Self> {
+        Self::recover_with_options(path, skiplist, options, WalOptions::default())
+    }
+
+    /// Like [`Wal::recover`], framing new batches according to `wal_options`,
+    /// which are checked before the file is opened.
+    pub fn recover_with_options(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        options: RecoverOptions,
+        wal_options: WalOptions,
+    ) -> Result<Self> {
+        wal_options.validate()?;
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)
+            .context("failed to recover from WAL")?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        if options.checksum_mode == ChecksumMode::Lenient && Self::is_torn_header(&buf) {
+            log::warn!(
+                "{}: treating a WAL with a torn header as empty",
+                path.display()
+            );
+            file.set_len(0)?;
+            return Ok(Self {
+                file: Arc::new(Mutex::new(BufWriter::new(file))),
+                max_batch_bytes: wal_options.max_batch_bytes,
+                header: None,
+            });
+        }
+        let (header, rbuf) = Self::split_header(&buf)?;
+        let good_len = Self::replay(path, rbuf, skiplist, options)?;
+        if good_len < rbuf.len() {
+            log::warn!(
+                "{}: truncating {} bytes after the last complete WAL batch",
+                path.display(),
+                rbuf.len() - good_len
+            );
+            file.set_len((HEADER_SIZE + good_len) as u64)?;
+        }
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            max_batch_bytes: wal_options.max_batch_bytes,
+            header: Some(header),
         })
     }
 
-    // pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
-    //     let path = path.as_ref();
-    //     let mut file = OpenOptions::new()
-    //         .read(true)
-    //         .append(true)
-    //         .open(path)
-    //         .context("failed to recover from WAL")?;
-    //     let mut buf = Vec::new();
-    //     file.read_to_end(&mut buf)?;
-    //     let mut rbuf: &[u8] = buf.as_slice();
-    //     while rbuf.has_remaining() {
-    //         let batch_size = rbuf.get_u32() as usize;
-    //         if rbuf.remaining() < batch_size {
-    //             bail!("incomplete WAL");
-    //         }
-    //         let mut batch_buf = &rbuf[..batch_size];
-    //         let mut kv_pairs = Vec::new();
-    //         let mut hasher = crc32fast::Hasher::new();
-    //         // The checksum computed from the individual components should be the same as a direct checksum on the buffer.
-    //         // Students' implementation only needs to do a single checksum on the buffer. We compute both for verification purpose.
-    //         let single_checksum = crc32fast::hash(batch_buf);
-    //         while batch_buf.has_remaining() {
-    //             let key_len = batch_buf.get_u16() as usize;
-    //             hasher.write(&(key_len as u16).to_be_bytes());
-    //             let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
-    //             hasher.write(&key);
-    //             batch_buf.advance(key_len);
-    //             let ts = batch_buf.get_u64();
-    //             hasher.write(&ts.to_be_bytes());
-    //             let value_len = batch_buf.get_u16() as usize;
-    //             hasher.write(&(value_len as u16).to_be_bytes());
-    //             let value = Bytes::copy_from_slice(&batch_buf[..value_len]);
-    //             hasher.write(&value);
-    //             kv_pairs.push((key, ts, value));
-    //             batch_buf.advance(value_len);
-    //         }
-    //         rbuf.advance(batch_size);
-    //         let expected_checksum = rbuf.get_u32();
-    //         let component_checksum = hasher.finalize();
-    //         assert_eq!(component_checksum, single_checksum);
-    //         if single_checksum != expected_checksum {
-    //             bail!("checksum mismatch");
-    //         }
-    //         for (key, ts, value) in kv_pairs {
-    //             skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
-    //         }
-    //     }
-    //     Ok(Self {
-    //         file: Arc::new(Mutex::new(BufWriter::new(file))),
-    //     })
-    // }
-
-    // pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-    //     self.put_batch(&[(key, value)])
-    // }
-
-    // pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
-    //     let mut file = self.file.lock();
-    //     let mut buf = Vec::<u8>::new();
-    //     for (key, value) in data {
-    //         buf.put_u16(key.key_len() as u16);
-    //         buf.put_slice(key.key_ref());
-    //         buf.put_u64(key.ts());
-    //         buf.put_u16(value.len() as u16);
-    //         buf.put_slice(value);
-    //     }
-    //     // write batch_size header (u32)
-    //     file.write_all(&(buf.len() as u32).to_be_bytes())?;
-    //     // write key-value pairs body
-    //     file.write_all(&buf)?;
-    //     // write checksum (u32)
-    //     file.write_all(&crc32fast::hash(&buf).to_be_bytes())?;
-    //     Ok(())
-    // }
+    // Split the next frame off `rbuf` and decode it. In strict mode, a
+    // truncated or corrupt frame is an error instead.
+    fn next_frame<'a, T>(
+        path: &Path,
+        rbuf: &mut &'a [u8],
+        options: RecoverOptions,
+        decode: impl FnOnce(&'a [u8]) -> Option<T>,
+    ) -> Result<Frame<T>> {
+        let frame_size = match rbuf.read_u32() {
+            Some(size) if rbuf.remaining() >= size as usize + 4 => size as usize,
+            _ => match options.checksum_mode {
+                ChecksumMode::Strict => bail!("incomplete WAL"),
+                ChecksumMode::Lenient => {
+                    log::warn!("{}: skipping incomplete WAL tail", path.display());
+                    return Ok(Frame::Truncated);
+                }
+            },
+        };
+        let frame = &rbuf[..frame_size];
+        rbuf.advance(frame_size);
+        let expected_checksum = rbuf.read_u32().unwrap();
+
+        let decoded = if crc32fast::hash(frame) == expected_checksum {
+            decode(frame)
+        } else {
+            None
+        };
+        match decoded {
+            Some(decoded) => Ok(Frame::Decoded(decoded)),
+            None => match options.checksum_mode {
+                ChecksumMode::Strict => bail!("checksum mismatch"),
+                ChecksumMode::Lenient => {
+                    log::warn!(
+                        "{}: skipping corrupt WAL batch of {} bytes",
+                        path.display(),
+                        frame_size
+                    );
+                    Ok(Frame::Corrupt)
+                }
+            },
+        }
+    }
+
+    // Frames start with a flags byte so that a batch can span several of
+    // them. A batch's frames are buffered and only replayed once its last
+    // frame is in, and a batch missing any frame is dropped whole.
+    //
+    // Returns the length of `rbuf` up to the end of the last complete batch.
+    fn replay(
+        path: &Path,
+        mut rbuf: &[u8],
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        options: RecoverOptions,
+    ) -> Result<usize> {
+        let len = rbuf.len();
+        let mut good_len = 0;
+        let decode = |frame: &[u8]| {
+            let (&flags, body) = frame.split_first()?;
+            Some((flags, Self::decode_batch(body)?))
+        };
+        let mut pending: Option<Vec<(KeyBytes, Bytes)>> = None;
+        while rbuf.remaining() > 0 {
+            let (flags, kv_pairs) = match Self::next_frame(path, &mut rbuf, options, decode)? {
+                Frame::Decoded(decoded) => decoded,
+                Frame::Corrupt => {
+                    // Only reachable in lenient mode. The rest of the batch is
+                    // skipped below, since nothing is pending for it.
+                    if pending.take().is_some() {
+                        log::warn!("{}: dropping a partly corrupt WAL batch", path.display());
+                    }
+                    continue;
+                }
+                Frame::Truncated => break,
+            };
+            let continues = flags & FRAME_CONTINUES != 0;
+            if continues != pending.is_some() {
+                match options.checksum_mode {
+                    ChecksumMode::Strict => bail!("incomplete WAL batch"),
+                    ChecksumMode::Lenient => {
+                        log::warn!("{}: dropping an incomplete WAL batch", path.display())
+                    }
+                }
+                if continues {
+                    continue;
+                }
+            }
+            if continues {
+                pending.as_mut().unwrap().extend(kv_pairs);
+            } else {
+                pending = Some(kv_pairs);
+            }
+            if flags & FRAME_MORE == 0 {
+                for (key, value) in pending.take().unwrap() {
+                    skiplist.insert(key, value);
+                }
+                good_len = len - rbuf.remaining();
+            }
+        }
+        if pending.is_some() {
+            match options.checksum_mode {
+                ChecksumMode::Strict => bail!("incomplete WAL"),
+                ChecksumMode::Lenient => {
+                    log::warn!("{}: skipping incomplete WAL tail", path.display())
+                }
+            }
+        }
+        Ok(good_len)
+    }
+
+    fn decode_batch(mut batch_buf: &[u8]) -> Option<Vec<(KeyBytes, Bytes)>> {
+        let mut kv_pairs = Vec::new();
+        while batch_buf.remaining() > 0 {
+            let key_len = batch_buf.read_u16()? as usize;
+            let key = Bytes::from(batch_buf.get(..key_len)?);
+            batch_buf.advance(key_len);
+            let ts = batch_buf.read_u64()?;
+            let value_len = batch_buf.read_u16()? as usize;
+            let value = Bytes::from(batch_buf.get(..value_len)?);
+            batch_buf.advance(value_len);
+            kv_pairs.push((KeyBytes::new(key, ts), value));
+        }
+        Some(kv_pairs)
+    }
+
+    pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
+        self.put_batch(&[(key, value)])
+    }
+
+    /// Append the key-value pairs as one batch. Each frame is the body length
+    /// (u32), the body, and a crc32 of the body (u32), where the body is a
+    /// flags byte followed by the entries. A batch over the configured
+    /// [`WalOptions::max_batch_bytes`] is split across several frames.
+    ///
+    /// Entries store their key and value lengths as u16s, so a batch with a
+    /// longer key or value is rejected before anything is written.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        if self.header.is_none() {
+            bail!("can't append to a WAL whose header was torn");
+        }
+        for (key, value) in data {
+            if key.key_len() > u16::MAX as usize {
+                bail!("key of {} bytes is too long for the WAL", key.key_len());
+            }
+            if value.len() > u16::MAX as usize {
+                bail!("value of {} bytes is too long for the WAL", value.len());
+            }
+        }
+        let mut file = self.file.lock().unwrap();
+        // The flags byte is filled in once it's known whether more frames follow.
+        let mut buf = vec![0];
+        let mut flags = 0;
+        for (key, value) in data {
+            let entry_size = 2 + key.key_len() + 8 + 2 + value.len();
+            if buf.len() > 1 && buf.len() - 1 + entry_size > self.max_batch_bytes {
+                buf[0] = flags | FRAME_MORE;
+                Self::write_frame(&mut file, &buf)?;
+                buf.truncate(1);
+                flags = FRAME_CONTINUES;
+            }
+            buf.put_u16(key.key_len() as u16);
+            buf.extend_from_slice(key.into_inner());
+            buf.put_u64(key.version());
+            buf.put_u16(value.len() as u16);
+            buf.extend_from_slice(value);
+        }
+        buf[0] = flags;
+        Self::write_frame(&mut file, &buf)
+    }
+
+    fn write_frame(file: &mut BufWriter<File>, frame: &[u8]) -> Result<()> {
+        // write frame_size header (u32)
+        file.write_all(&(frame.len() as u32).to_be_bytes())?;
+        // write flags and key-value pairs body
+        file.write_all(frame)?;
+        // write checksum (u32)
+        file.write_all(&crc32fast::hash(frame).to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Get the size of the log file, including any bytes still buffered.
+    pub fn size(&self) -> Result<u64> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+        Ok(file.get_ref().metadata()?.len())
+    }
 
     pub fn sync(&self) -> Result<()> {
         let mut file = self.file.lock().unwrap();
-        file.get_ref().flush()?;
+        file.flush()?;
         file.get_mut().sync_all()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::key::Key;
+
+    use super::*;
+
+    fn write_three_batches_and_corrupt_middle(path: &Path) {
+        let wal = Wal::new(path, 0).unwrap();
+        for key in [b"k1", b"k2", b"k3"] {
+            wal.put(Key::from_slice(key, 1), b"v").unwrap();
+        }
+        wal.sync().unwrap();
+        drop(wal);
+
+        // After the header, each batch is 4 (size) + 1 (flags) + 15 (entry)
+        // + 4 (checksum) bytes, so the second batch's key starts at
+        // HEADER_SIZE + 24 + 4 + 1 + 2.
+        let mut data = std::fs::read(path).unwrap();
+        assert_eq!(data.len(), HEADER_SIZE + 3 * 24);
+        data[HEADER_SIZE + 24 + 4 + 1 + 2] ^= 0xff;
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_wal_recover() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::new(&path, 0).unwrap();
+        wal.put_batch(&[
+            (Key::from_slice(b"key1", 1), b"value1"),
+            (Key::from_slice(b"key2", 2), b"value2"),
+        ])
+        .unwrap();
+        wal.put(Key::from_slice(b"key1", 3), b"value3").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        assert_eq!(map.len(), 3);
+        let get = |key: &'static [u8], ts| {
+            map.get(&KeyBytes::new(Bytes::from_static(key), ts))
+                .map(|e| e.value().as_ref().to_vec())
+        };
+        assert_eq!(get(b"key1", 1).unwrap(), b"value1");
+        assert_eq!(get(b"key2", 2).unwrap(), b"value2");
+        assert_eq!(get(b"key1", 3).unwrap(), b"value3");
+    }
+
+    #[test]
+    fn test_wal_recover_empty_key() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::new(&path, 0).unwrap();
+        wal.put(Key::from_slice(b"", 1), b"value").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        let entry = map.front().unwrap();
+        assert!(entry.key().is_empty());
+        assert_eq!(entry.key().version(), 1);
+        assert_eq!(entry.value().as_ref(), b"value");
+    }
+
+    #[test]
+    fn test_wal_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::new(&path, 0).unwrap();
+        assert_eq!(wal.size().unwrap(), HEADER_SIZE as u64);
+
+        // 4 (size) + 1 (flags) + 2 + 4 (key) + 8 (version) + 2 + 6 (value)
+        // + 4 (checksum)
+        wal.put(Key::from_slice(b"key1", 1), b"value1").unwrap();
+        assert_eq!(wal.size().unwrap(), HEADER_SIZE as u64 + 31);
+
+        // One more frame holding two 22-byte entries.
+        wal.put_batch(&[
+            (Key::from_slice(b"key2", 2), b"value2"),
+            (Key::from_slice(b"key3", 3), b"value3"),
+        ])
+        .unwrap();
+        assert_eq!(
+            wal.size().unwrap(),
+            HEADER_SIZE as u64 + 31 + 4 + 1 + 2 * 22 + 4
+        );
+    }
+
+    #[test]
+    fn test_wal_recover_strict() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        write_three_batches_and_corrupt_middle(&path);
+
+        let map = SkipMap::new();
+        let err = Wal::recover(&path, &map, RecoverOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "checksum mismatch");
+    }
+
+    #[test]
+    fn test_wal_recover_lenient() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        write_three_batches_and_corrupt_middle(&path);
+
+        let map = SkipMap::new();
+        let options = RecoverOptions {
+            checksum_mode: ChecksumMode::Lenient,
+        };
+        Wal::recover(&path, &map, options).unwrap();
+        let keys: Vec<_> = map.iter().map(|e| e.key().into_inner().to_vec()).collect();
+        assert_eq!(keys, vec![b"k1".to_vec(), b"k3".to_vec()]);
+    }
+
+    #[test]
+    fn test_wal_recover_lenient_truncates() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::new(&path, 0).unwrap();
+        wal.put(Key::from_slice(b"k1", 1), b"v").unwrap();
+        wal.put(Key::from_slice(b"k2", 1), b"v").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let data = std::fs::read(&path).unwrap();
+        let options = RecoverOptions {
+            checksum_mode: ChecksumMode::Lenient,
+        };
+
+        // A corrupt last frame, and a torn one.
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        for bad in [corrupt, data[..data.len() - 3].to_vec()] {
+            std::fs::write(&path, &bad).unwrap();
+            let wal = Wal::recover(&path, &SkipMap::new(), options).unwrap();
+            wal.put(Key::from_slice(b"k3", 1), b"v").unwrap();
+            wal.sync().unwrap();
+            drop(wal);
+
+            for options in [options, RecoverOptions::default()] {
+                let map = SkipMap::new();
+                Wal::recover(&path, &map, options).unwrap();
+                let keys: Vec<_> = map.iter().map(|e| e.key().into_inner().to_vec()).collect();
+                assert_eq!(keys, vec![b"k1".to_vec(), b"k3".to_vec()]);
+            }
+        }
+    }
+
+    fn recover_err(path: &Path) -> String {
+        Wal::recover(path, &SkipMap::new(), RecoverOptions::default())
+            .err()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_wal_header() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("7.wal");
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let wal = Wal::new(&path, 7).unwrap();
+        let header = wal.header().unwrap();
+        assert_eq!(header.memtable_id, 7);
+        assert!(header.created_at >= before);
+        wal.put(Key::from_slice(b"key1", 1), b"value1").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        assert_eq!(Wal::read_header(&path).unwrap(), Some(header));
+
+        let map = SkipMap::new();
+        let wal = Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        assert_eq!(wal.header(), Some(header));
+        assert_eq!(map.len(), 1);
+
+        let data = std::fs::read(&path).unwrap();
+        let mut bad = data.clone();
+        bad[WAL_MAGIC.len() + 1] ^= 0xff;
+        std::fs::write(&path, &bad).unwrap();
+        assert_eq!(recover_err(&path), "corrupt WAL header");
+        assert!(Wal::read_header(&path).is_err());
+
+        let mut bad = data.clone();
+        bad[WAL_MAGIC.len()] = 2;
+        let checksum = crc32fast::hash(&bad[..HEADER_SIZE - 4]);
+        bad[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+        std::fs::write(&path, &bad).unwrap();
+        assert_eq!(recover_err(&path), "unsupported WAL format version 2");
+
+        std::fs::write(&path, &data[..HEADER_SIZE - 1]).unwrap();
+        assert_eq!(recover_err(&path), "truncated WAL header");
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(recover_err(&path), "missing WAL header");
+        std::fs::write(&path, b"garbage").unwrap();
+        assert_eq!(recover_err(&path), "unrecognized WAL header");
+    }
+
+    #[test]
+    fn test_wal_recover_torn_header() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        drop(Wal::new(&path, 0).unwrap());
+        let data = std::fs::read(&path).unwrap();
+        let options = RecoverOptions {
+            checksum_mode: ChecksumMode::Lenient,
+        };
+
+        for len in [0, 2, HEADER_SIZE - 1] {
+            std::fs::write(&path, &data[..len]).unwrap();
+            assert_eq!(Wal::read_header(&path).unwrap(), None);
+            assert!(Wal::recover(&path, &SkipMap::new(), RecoverOptions::default()).is_err());
+
+            let map = SkipMap::new();
+            let wal = Wal::recover(&path, &map, options).unwrap();
+            assert!(map.is_empty());
+            assert_eq!(wal.header(), None);
+            let err = wal.put(Key::from_slice(b"key1", 1), b"value1").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "can't append to a WAL whose header was torn"
+            );
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        }
+    }
+
+    #[test]
+    fn test_wal_put_too_long() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::new(&path, 0).unwrap();
+        let long = vec![b'x'; u16::MAX as usize + 1];
+        let max = &long[..u16::MAX as usize];
+
+        let err = wal
+            .put_batch(&[
+                (Key::from_slice(b"key1", 1), b"value1"),
+                (Key::from_slice(&long, 1), b"value2"),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key of 65536 bytes is too long for the WAL"
+        );
+        let err = wal.put(Key::from_slice(b"key1", 1), &long).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "value of 65536 bytes is too long for the WAL"
+        );
+        assert_eq!(wal.size().unwrap(), HEADER_SIZE as u64);
+
+        wal.put(Key::from_slice(max, 1), max).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        let entry = map.front().unwrap();
+        assert_eq!(entry.key().into_inner(), max);
+        assert_eq!(entry.value().as_ref(), max);
+    }
+
+    fn put_split_batch(path: &Path) -> Vec<u8> {
+        // 22 bytes per entry, so a 50 byte limit fits two per frame.
+        let wal = Wal::new_with_options(
+            path,
+            0,
+            WalOptions {
+                max_batch_bytes: 50,
+            },
+        )
+        .unwrap();
+        let keys: Vec<_> = (0..5).map(|i| format!("key{}", i)).collect();
+        let batch: Vec<_> = keys
+            .iter()
+            .map(|key| (Key::from_slice(key.as_bytes(), 1), &b"value1"[..]))
+            .collect();
+        wal.put_batch(&batch).unwrap();
+        wal.put(Key::from_slice(b"key9", 1), b"value9").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let data = std::fs::read(path).unwrap();
+        // Frames of 2, 2 and 1 entries, then the single put.
+        let frame = |entries: usize| 4 + 1 + entries * 22 + 4;
+        assert_eq!(
+            data.len(),
+            HEADER_SIZE + frame(2) + frame(2) + frame(1) + frame(1)
+        );
+        data
+    }
+
+    #[test]
+    fn test_wal_split_batch() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let data = put_split_batch(&path);
+
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        assert_eq!(map.len(), 6);
+
+        // Losing the middle frame loses the whole batch, but not the next one.
+        let mut corrupt = data.clone();
+        corrupt[HEADER_SIZE + 53 + 4 + 1 + 2] ^= 0xff;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = Wal::recover(&path, &SkipMap::new(), RecoverOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "checksum mismatch");
+        let map = SkipMap::new();
+        let options = RecoverOptions {
+            checksum_mode: ChecksumMode::Lenient,
+        };
+        Wal::recover(&path, &map, options).unwrap();
+        let keys: Vec<_> = map.iter().map(|e| e.key().into_inner().to_vec()).collect();
+        assert_eq!(keys, vec![b"key9".to_vec()]);
+
+        // So does a crash before its last frame is written.
+        std::fs::write(&path, &data[..HEADER_SIZE + 2 * 53]).unwrap();
+        let err = Wal::recover(&path, &SkipMap::new(), RecoverOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "incomplete WAL");
+        let map = SkipMap::new();
+        let wal = Wal::recover(&path, &map, options).unwrap();
+        assert!(map.is_empty());
+
+        // The dangling frames are cut off, so the next batch is readable even
+        // in strict mode.
+        wal.put(Key::from_slice(b"key9", 2), b"value9").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let map = SkipMap::new();
+        Wal::recover(&path, &map, RecoverOptions::default()).unwrap();
+        let keys: Vec<_> = map.iter().map(|e| e.key().into_inner().to_vec()).collect();
+        assert_eq!(keys, vec![b"key9".to_vec()]);
+    }
+
+    #[test]
+    fn test_wal_options() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        for max_batch_bytes in [0, MAX_FRAME_BYTES + 1] {
+            let options = WalOptions { max_batch_bytes };
+            let err = Wal::new_with_options(&path, 0, options).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("max batch bytes {} out of range", max_batch_bytes)
+            );
+            // Bad options are caught before the file is created.
+            assert!(!path.exists());
+        }
+
+        // A recovered WAL splits batches the same way as a new one.
+        let data = put_split_batch(&path);
+        std::fs::write(&path, &data[..HEADER_SIZE]).unwrap();
+        let options = WalOptions {
+            max_batch_bytes: 50,
+        };
+        let wal = Wal::recover_with_options(
+            &path,
+            &SkipMap::new(),
+            RecoverOptions::default(),
+            WalOptions { max_batch_bytes: 0 },
+        );
+        assert!(wal.is_err());
+        let wal =
+            Wal::recover_with_options(&path, &SkipMap::new(), RecoverOptions::default(), options)
+                .unwrap();
+        let keys: Vec<_> = (0..5).map(|i| format!("key{}", i)).collect();
+        let batch: Vec<_> = keys
+            .iter()
+            .map(|key| (Key::from_slice(key.as_bytes(), 1), &b"value1"[..]))
+            .collect();
+        wal.put_batch(&batch).unwrap();
+        wal.sync().unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            data[..HEADER_SIZE + 3 * 53 - 22]
+        );
+    }
+}